@@ -1,5 +1,5 @@
 use std::fs::{self, File};
-use std::io::{self, BufReader, BufWriter, Write};
+use std::io::{self, BufReader, BufWriter};
 use std::path::{Path, PathBuf, Component};
 use tar::{Builder, Archive};
 use zstd::stream::{Encoder, Decoder};
@@ -26,10 +26,8 @@ pub fn compress_dir_to_zstd(src_dir: &str, dst_file: &str, level: i32) -> std::i
 
         if path.is_file() {
             tar_builder.append_path_with_name(path, relative_path)?;
-        } else if path.is_dir() {
-            if relative_path.as_os_str().len() > 0 {
-                tar_builder.append_dir(relative_path, path)?;
-            }
+        } else if path.is_dir() && !relative_path.as_os_str().is_empty() {
+            tar_builder.append_dir(relative_path, path)?;
         }
     }
 
@@ -67,7 +65,7 @@ fn sanitize_entry_path(entry_path: &Path, dest: &Path) -> io::Result<PathBuf> {
 pub fn decompress_zstd_to_dir(src_file: &str, dst_dir: &str) -> io::Result<()> {
     let dst_dir_path = Path::new(dst_dir);
 
-    fs::create_dir_all(&dst_dir_path)?;
+    fs::create_dir_all(dst_dir_path)?;
 
     let file = File::open(src_file)?;
     let buf = BufReader::new(file);
@@ -77,7 +75,7 @@ pub fn decompress_zstd_to_dir(src_file: &str, dst_dir: &str) -> io::Result<()> {
     for entry in archive.entries()? {
         let mut entry = entry?;
         let entry_path = entry.path()?;
-        let safe_path = sanitize_entry_path(&entry_path, &dst_dir_path)?;
+        let safe_path = sanitize_entry_path(&entry_path, dst_dir_path)?;
 
         let header = entry.header().clone();
 
@@ -87,7 +85,7 @@ pub fn decompress_zstd_to_dir(src_file: &str, dst_dir: &str) -> io::Result<()> {
         }
 
         if header.entry_type().is_symlink() {
-            return Err(io::Error::new(io::ErrorKind::Other, "Symlinks not allowed in archive"));
+            return Err(io::Error::other("Symlinks not allowed in archive"));
         }
 
         if let Some(parent) = safe_path.parent() {
@@ -101,7 +99,7 @@ pub fn decompress_zstd_to_dir(src_file: &str, dst_dir: &str) -> io::Result<()> {
         {
             use std::os::unix::fs::PermissionsExt;
             if let Ok(mode) = header.mode() {
-                let perm = fs::Permissions::from_mode(mode as u32);
+                let perm = fs::Permissions::from_mode(mode);
                 out.set_permissions(perm)?;
             }
         }
@@ -0,0 +1,215 @@
+use std::fmt;
+use std::path::PathBuf;
+use mlua::Lua;
+use rlimit::Resource;
+use serde::Serialize;
+
+use crate::engine::exec_engine::ZephirEngine;
+use crate::engine::{wasm_cache, wasm_guest, wasm_limits};
+use crate::models::config;
+use crate::utils::os::{os_cgroup, os_info, os_probe::{self, Feature}};
+
+/// Isolation features and runtimes available on the host.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostReport {
+    pub root: bool,
+    pub uid: u32,
+    pub user_namespaces: Feature,
+    pub cgroup: Feature,
+    pub seccomp: Feature,
+    pub landlock: Feature,
+    pub overlayfs: Feature,
+    pub cache_reflink: Feature,
+    pub sandbox_reflink: Feature,
+    /// Key of the wasmtime engine the config's WASM guests run on, which names
+    /// their entries in the compile cache.
+    pub wasmtime_engine: String,
+    pub luajit_version: String,
+}
+
+/// What a config asks for that the host cannot provide.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Preflight {
+    /// Required isolation that is unavailable; the function is not started.
+    pub problems: Vec<String>,
+    /// Isolation that will be weaker than configured.
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DoctorReport {
+    pub host: HostReport,
+    /// Checks of the config `doctor` was given, if any.
+    pub preflight: Option<Preflight>,
+}
+
+fn cgroup_parent(config: &config::ZephirConfig) -> PathBuf {
+    config.function.resources.cgroup.parent
+        .as_ref()
+        .map(Into::into)
+        .unwrap_or_else(|| os_cgroup::default_parent(os_info::has_root_privilege()))
+}
+
+fn wasmtime_engine(config: &config::ZephirConfig) -> String {
+    let fuel = wasm_limits::fuel_budget(&config.function.resources).is_some();
+    match wasm_guest::engine(fuel) {
+        Ok(engine) => wasm_cache::engine_key(&engine),
+        Err(e) => format!("unavailable ({e:#})"),
+    }
+}
+
+fn luajit_version() -> String {
+    Lua::new()
+        .load("return jit and jit.version or _VERSION")
+        .eval::<String>()
+        .unwrap_or_else(|e| format!("unknown ({e})"))
+}
+
+/// Probe everything `zephir doctor` reports, using the directories and cgroup parent from `config`.
+pub fn probe_host(config: &config::ZephirConfig) -> HostReport {
+    let (cache, sandbox) = config.storage_dirs();
+    HostReport {
+        root: os_info::has_root_privilege(),
+        uid: nix::unistd::getuid().as_raw(),
+        user_namespaces: os_probe::user_namespaces(),
+        cgroup: os_probe::cgroup_delegation(&cgroup_parent(config)),
+        seccomp: os_probe::seccomp(),
+        landlock: os_probe::landlock(),
+        overlayfs: os_probe::overlayfs(),
+        cache_reflink: os_probe::reflink(&cache),
+        sandbox_reflink: os_probe::reflink(&sandbox),
+        wasmtime_engine: wasmtime_engine(config),
+        luajit_version: luajit_version(),
+    }
+}
+
+/// Check the engine's config against the host, probing only what the config needs.
+pub fn preflight(engine: &ZephirEngine) -> Preflight {
+    let config = &engine.config;
+    let function = &config.function;
+    let resources = &function.resources;
+    let is_root = os_info::has_root_privilege();
+    let native = matches!(function.bundle.artifactType, config::ArtifactType::NATIVE);
+    let mut preflight = Preflight::default();
+
+    // runAs.userNamespace always takes a user namespace; an unprivileged caller
+    // also needs one to own a network or mount namespace.
+    let mut needs_user_namespace = Vec::new();
+    if native {
+        if function.runAs.userNamespace.is_some() {
+            needs_user_namespace.push("runAs.userNamespace");
+        }
+        if !is_root && function.network.mode != config::NetworkMode::Host {
+            needs_user_namespace.push("network.mode");
+        }
+        if !is_root && !function.mounts.is_empty() {
+            needs_user_namespace.push("mounts");
+        }
+    }
+    if !needs_user_namespace.is_empty() {
+        let user_namespaces = os_probe::user_namespaces();
+        if !user_namespaces.available {
+            preflight.problems.push(format!(
+                "{} needs a user namespace, which this host does not allow: {}",
+                needs_user_namespace.join(", "),
+                user_namespaces.detail
+            ));
+        }
+    }
+
+    // Nothing available to a NATIVE process filters connections by address, so
+    // running one under an allowlist would leave it unrestricted.
+    if native && function.network.mode == config::NetworkMode::Host && !function.network.allow.is_empty() {
+        preflight.problems.push(
+            "network.allow cannot be enforced for NATIVE functions; use network.mode none or loopback, or drop the allowlist".to_string(),
+        );
+    }
+
+    // Raising a hard limit takes CAP_SYS_RESOURCE, so an unprivileged caller cannot go above it.
+    if !is_root {
+        let mut limits = vec![
+            ("cpuLimit", Resource::CPU, Some(resources.cpuLimit.saturating_add(1))),
+            ("storage", Resource::FSIZE, Some(resources.storage)),
+            ("maxOpenFiles", Resource::NOFILE, resources.maxOpenFiles),
+            ("maxStack", Resource::STACK, resources.maxStack),
+        ];
+        // The WASM worker reserves large address spaces and so runs without RLIMIT_AS.
+        if !matches!(function.bundle.artifactType, config::ArtifactType::WASM) {
+            limits.push(("memory", Resource::AS, Some(resources.memory)));
+        }
+        // RLIMIT_NPROC is only set for functions in their own user namespace.
+        if !needs_user_namespace.is_empty() && let Some((limit, name)) = engine.task_limit() {
+            limits.push((name, Resource::NPROC, Some(limit)));
+        }
+
+        for (name, resource, wanted) in limits {
+            if let (Some(wanted), Some(hard)) = (wanted, os_probe::hard_limit(resource)) && wanted > hard {
+                preflight.problems.push(format!("resources.{name} needs {wanted}, above this host's hard {} limit of {hard}", resource.as_name()));
+            }
+        }
+    }
+
+    // Not even root may raise RLIMIT_NOFILE above fs.nr_open.
+    let nr_open = std::fs::read_to_string("/proc/sys/fs/nr_open").ok().and_then(|v| v.trim().parse::<u64>().ok());
+    if let (Some(files), Some(nr_open)) = (resources.maxOpenFiles, nr_open) && files > nr_open {
+        preflight.problems.push(format!("resources.maxOpenFiles needs {files}, above this host's fs.nr_open of {nr_open}"));
+    }
+
+    let cgroup = &resources.cgroup;
+    if cgroup.enabled && (cgroup.cpus.is_some() || !cgroup.io.is_empty()) {
+        let delegation = os_probe::cgroup_delegation(&cgroup_parent(config));
+        if !delegation.available {
+            preflight.warnings.push(format!(
+                "resources.cgroup.cpus and io are only enforced through cgroup v2, which is not usable here: {}",
+                delegation.detail
+            ));
+        }
+    }
+
+    preflight
+}
+
+fn write_feature(f: &mut fmt::Formatter<'_>, name: &str, feature: &Feature) -> fmt::Result {
+    writeln!(f, "{name:<18} {}: {}", if feature.available { "yes" } else { "no" }, feature.detail)
+}
+
+impl fmt::Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let host = &self.host;
+        writeln!(f, "{:<18} {} (uid {})", "root", if host.root { "yes" } else { "no" }, host.uid)?;
+        write_feature(f, "user namespaces", &host.user_namespaces)?;
+        write_feature(f, "cgroup v2", &host.cgroup)?;
+        write_feature(f, "seccomp", &host.seccomp)?;
+        write_feature(f, "landlock", &host.landlock)?;
+        write_feature(f, "overlayfs", &host.overlayfs)?;
+        write_feature(f, "reflink (cache)", &host.cache_reflink)?;
+        write_feature(f, "reflink (sandbox)", &host.sandbox_reflink)?;
+        writeln!(f, "{:<18} engine {}", "wasmtime", host.wasmtime_engine)?;
+        writeln!(f, "{:<18} {}", "luajit", host.luajit_version)?;
+
+        if let Some(preflight) = &self.preflight {
+            writeln!(f)?;
+            if preflight.problems.is_empty() && preflight.warnings.is_empty() {
+                writeln!(f, "The config's isolation requirements are met.")?;
+            }
+            for problem in &preflight.problems {
+                writeln!(f, "error: {problem}")?;
+            }
+            for warning in &preflight.warnings {
+                writeln!(f, "warning: {warning}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DoctorReport {
+    pub fn new(host: HostReport, preflight: Option<Preflight>) -> Self {
+        Self { host, preflight }
+    }
+
+    pub fn has_problems(&self) -> bool {
+        self.preflight.as_ref().is_some_and(|preflight| !preflight.problems.is_empty())
+    }
+}
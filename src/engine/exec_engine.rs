@@ -1,43 +1,306 @@
 use tokio::{
-    io::{AsyncBufReadExt, BufReader},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     join,
+    net::UnixStream,
     process,
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    time::{sleep_until, Instant},
 };
-use std::{fs, io, path::Path, process::Stdio};
-use log::{info, error};
+use std::{collections::BTreeMap, fs, io::{self, Read, Write}, path::{Path, PathBuf}, process::{ExitStatus, Stdio}};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{info, warn, error};
 use wasmtime::*;
 use wasmtime_wasi::{DirPerms, FilePerms, WasiCtxBuilder};
-use mlua::{Lua, StdLib, LuaOptions};
+use wasmtime_wasi::p2::pipe::MemoryInputPipe;
+use wasmtime_wasi::sockets::SocketAddrUse;
+use mlua::{HookTriggers, Lua, LuaOptions, LuaSerdeExt, StdLib, VmState};
+use nix::sys::signal::Signal;
 use thiserror::Error;
 
+use crate::engine::doctor_engine;
+use crate::engine::guest_interrupt::GuestInterrupt;
+use crate::engine::output_pump::OutputPump;
+use crate::engine::wasm_cache::CompileCache;
+use crate::engine::wasm_guest::{self, GuestRun, StoreSetup};
+use crate::engine::wasm_limits::{self, GuestLimits};
 use crate::models::config;
-use crate::utils::fs::{fs_crud, path};
+use crate::models::protocol::{WorkerError, WorkerErrorKind, WorkerMessage, WorkerRequest, WorkerUsage};
+use crate::models::result::{CapturedOutput, Failure, FailureKind, InvocationResult, OutputStream, OutputTimeline};
+use crate::utils::fs::{dotenv, fs_crud, path};
 use crate::compress::compress_zstd;
-use crate::utils::os::{os_info, os_sandbox};
+use crate::utils::net::cidr::Cidr;
+use crate::utils::os::{os_cgroup, os_info, os_landlock, os_mount, os_namespace, os_process, os_sandbox, os_storage};
 
 #[derive(Error, Debug)]
 pub enum ZephirInvokationError {
     #[error("IO error: {0}")]
     Io(#[from] io::Error),
 
+    /// A trap, or another error while the guest ran.
     #[error("WASM execution error: {0}")]
-    Wasm(#[from] wasmtime::Error),
+    Wasm(wasmtime::Error),
+
+    /// The guest failed to compile, validate, link or instantiate, or has no entry point.
+    #[error("WASM guest could not be loaded: {0}")]
+    WasmLoad(wasmtime::Error),
 
     #[error("Lua execution error: {0}")]
     Lua(#[from] mlua::Error),
 
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    #[error("Sandbox setup failed: {0}")]
+    Sandbox(io::Error),
+
+    #[error("Memory limit exceeded: {0}")]
+    MemoryLimit(String),
+
+    #[error("Resource limit exceeded: {0}")]
+    ResourceLimit(String),
+
+    #[error("Timed out after {0:?}")]
+    Timeout(Duration),
+
+    #[error("Interrupted: {0}")]
+    Interrupted(String),
+
+    #[error("Process exited with {0}")]
+    Exited(ExitStatus),
+
     #[error("Other error: {0}")]
     Other(String),
 }
 
+impl From<&ZephirInvokationError> for WorkerError {
+    fn from(error: &ZephirInvokationError) -> Self {
+        let kind = match error {
+            ZephirInvokationError::Io(_) => WorkerErrorKind::Io,
+            ZephirInvokationError::Wasm(_) => WorkerErrorKind::Wasm,
+            ZephirInvokationError::WasmLoad(_) => WorkerErrorKind::WasmLoad,
+            ZephirInvokationError::Lua(_) => WorkerErrorKind::Lua,
+            ZephirInvokationError::Config(_) => WorkerErrorKind::Config,
+            ZephirInvokationError::Sandbox(_) => WorkerErrorKind::Sandbox,
+            ZephirInvokationError::MemoryLimit(_) => WorkerErrorKind::MemoryLimit,
+            ZephirInvokationError::ResourceLimit(_) => WorkerErrorKind::ResourceLimit,
+            ZephirInvokationError::Timeout(_) => WorkerErrorKind::Timeout,
+            ZephirInvokationError::Interrupted(_) => WorkerErrorKind::Interrupted,
+            ZephirInvokationError::Exited(_) => WorkerErrorKind::Exit,
+            ZephirInvokationError::Other(_) => WorkerErrorKind::Other,
+        };
+        let message = match error {
+            ZephirInvokationError::Io(e) | ZephirInvokationError::Sandbox(e) => e.to_string(),
+            ZephirInvokationError::Wasm(e) | ZephirInvokationError::WasmLoad(e) => format!("{e:#}"),
+            ZephirInvokationError::Lua(e) => e.to_string(),
+            ZephirInvokationError::Timeout(d) => format!("timed out after {d:?}"),
+            ZephirInvokationError::Exited(status) => format!("process exited with {status}"),
+            ZephirInvokationError::Config(m)
+            | ZephirInvokationError::MemoryLimit(m)
+            | ZephirInvokationError::ResourceLimit(m)
+            | ZephirInvokationError::Interrupted(m)
+            | ZephirInvokationError::Other(m) => m.clone(),
+        };
+        let exit_code = match error {
+            ZephirInvokationError::Exited(status) => status.code(),
+            _ => None,
+        };
+        let timeout_ms = match error {
+            ZephirInvokationError::Timeout(timeout) => Some(timeout.as_millis() as u64),
+            _ => None,
+        };
+        Self { kind, message, exit_code, timeout_ms }
+    }
+}
+
+impl ZephirInvokationError {
+    pub fn category(&self) -> FailureKind {
+        match self {
+            Self::Io(_) => FailureKind::Io,
+            Self::Wasm(_) | Self::Lua(_) => FailureKind::Trap,
+            Self::Config(_) | Self::WasmLoad(_) => FailureKind::Config,
+            Self::Sandbox(_) => FailureKind::Sandbox,
+            Self::MemoryLimit(_) => FailureKind::MemoryLimit,
+            Self::ResourceLimit(_) => FailureKind::ResourceLimit,
+            Self::Timeout(_) => FailureKind::Timeout,
+            Self::Interrupted(_) => FailureKind::Interrupted,
+            Self::Exited(status) if status.signal().is_some() => FailureKind::Signal,
+            Self::Exited(_) => FailureKind::Exit,
+            Self::Other(_) => FailureKind::Other,
+        }
+    }
+}
+
+/// Rebuilds the error a worker reported, keeping its category.
+impl From<WorkerError> for ZephirInvokationError {
+    fn from(error: WorkerError) -> Self {
+        match error.kind {
+            WorkerErrorKind::Io => Self::Io(io::Error::other(error.message)),
+            WorkerErrorKind::Wasm => Self::Wasm(wasmtime::Error::msg(error.message)),
+            WorkerErrorKind::WasmLoad => Self::WasmLoad(wasmtime::Error::msg(error.message)),
+            WorkerErrorKind::Lua => Self::Lua(mlua::Error::runtime(error.message)),
+            WorkerErrorKind::Config => Self::Config(error.message),
+            WorkerErrorKind::Sandbox => Self::Sandbox(io::Error::other(error.message)),
+            WorkerErrorKind::MemoryLimit => Self::MemoryLimit(error.message),
+            WorkerErrorKind::ResourceLimit => Self::ResourceLimit(error.message),
+            WorkerErrorKind::Interrupted => Self::Interrupted(error.message),
+            WorkerErrorKind::Timeout => Self::Timeout(Duration::from_millis(error.timeout_ms.unwrap_or_default())),
+            WorkerErrorKind::Exit => Self::Exited(ExitStatus::from_raw(error.exit_code.unwrap_or(1) << 8)),
+            WorkerErrorKind::Other => Self::Other(error.message),
+        }
+    }
+}
+
+/// How often sandbox storage and the process tree are measured while a function runs.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the function's process tree is walked for processes it started,
+/// so that they are still found after leaving its session and being orphaned.
+const TRACK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long output is still read after the function exited. Anything that
+/// keeps its pipes open past that is killed rather than waited for.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Bytes read from the function's stdout or stderr at a time.
+const READ_CHUNK: usize = 8 * 1024;
+
+/// Lua instructions between checks for a pending interruption.
+const LUA_INTERRUPT_CHECK_INTERVAL: u32 = 10_000;
+
+async fn sleep_until_some(at: Option<Instant>) {
+    match at {
+        Some(at) => sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Run blocking file work on tokio's blocking pool.
+async fn unblock<T: Send + 'static>(work: impl FnOnce() -> io::Result<T> + Send + 'static) -> io::Result<T> {
+    tokio::task::spawn_blocking(work).await.map_err(io::Error::other)?
+}
+
+/// The fd a `zephir worker` finds its control socket on.
+pub const WORKER_FD: RawFd = 3;
+
+/// Control socket of a `zephir worker` child and the request to send over it.
+struct WorkerSocket {
+    parent_end: StdUnixStream,
+    worker_end: StdUnixStream,
+    request_line: Vec<u8>,
+}
+
+/// What a single invocation is called with.
+#[derive(Debug, Default, Clone)]
+pub struct Invocation {
+    /// Arguments for NATIVE functions.
+    pub args: Vec<String>,
+    /// Event payload: delivered on stdin for NATIVE and WASM, as the `event`
+    /// global for Lua.
+    pub payload: Option<Vec<u8>>,
+}
+
+/// Everything observed while supervising a child process.
+#[derive(Default)]
+struct Supervised {
+    status: ExitStatus,
+    cgroup_stats: Option<os_cgroup::CgroupStats>,
+    worker_usage: Option<WorkerUsage>,
+    /// `Some` once the worker sent its result message.
+    worker_result: Option<Option<WorkerError>>,
+    /// The wall-clock timeout hit and the process group was terminated.
+    timed_out: bool,
+    /// [`ZephirEngine::cancel`] was called and the process group was terminated.
+    cancelled: bool,
+    /// Polling found the sandbox over its storage quota and killed the process group.
+    storage_exceeded: bool,
+    storage_usage: Option<os_storage::StorageUsage>,
+    /// rusage of the process tree, once it has been reaped.
+    rusage: Option<os_process::ProcessUsage>,
+    /// Highest process, thread and open file counts seen while polling.
+    census: os_process::Census,
+    stdout: CapturedOutput,
+    stderr: CapturedOutput,
+    timeline: OutputTimeline,
+}
+
+/// `runAs` resolved against the host.
+#[derive(Debug, Default)]
+struct RunAs {
+    /// Ids the function switches to, as seen inside its user namespace if it has one.
+    identity: Option<os_sandbox::Identity>,
+    /// Maps for a user namespace (NATIVE only).
+    id_maps: Option<os_namespace::IdMaps>,
+    /// Host ids that must own the sandbox so the function can write to it.
+    owner: Option<(u32, u32)>,
+}
+
+/// uid and gid of nobody/nogroup, which root drops to by default.
+const NOBODY: u32 = 65534;
+
+/// Where WASM guests see their sandbox directory.
+const WASM_SANDBOX_DIR: &str = "/sandbox";
+
+/// A mount `target` as a path relative to the sandbox, refusing anything that
+/// would point outside it.
+fn sandbox_relative(target: &str) -> Result<PathBuf, ZephirInvokationError> {
+    let relative: PathBuf = Path::new(target)
+        .components()
+        .filter(|component| !matches!(component, std::path::Component::RootDir | std::path::Component::CurDir))
+        .collect();
+    let inside = relative.components().all(|component| matches!(component, std::path::Component::Normal(_)));
+    if !inside || relative.as_os_str().is_empty() {
+        return Err(ZephirInvokationError::Config(format!("mount target {target} must be a path inside the sandbox")));
+    }
+    Ok(relative)
+}
+
 #[derive(Debug)]
 pub struct ZephirEngine {
     pub config: config::ZephirConfig,
+    interrupt: Arc<GuestInterrupt>,
+    /// Runtime counters of the guest run by [`Self::run_guest`].
+    guest_usage: Mutex<WorkerUsage>,
+    cancel: watch::Sender<bool>,
 }
 
 impl ZephirEngine {
     pub fn new(config: config::ZephirConfig) -> Self {
-        Self { config }
+        Self {
+            config,
+            interrupt: Arc::new(GuestInterrupt::default()),
+            guest_usage: Mutex::default(),
+            cancel: watch::Sender::new(false),
+        }
+    }
+
+    /// Stop the function being invoked the way a timeout would (SIGTERM, then
+    /// SIGKILL after the grace period). The invocation still finishes, cleans up
+    /// and reports an interrupted run; one cancelled before the function starts
+    /// never starts it.
+    pub fn cancel(&self) {
+        self.cancel.send_replace(true);
+    }
+
+    /// Counters collected while running the guest, for the worker to report.
+    pub fn guest_usage(&self) -> WorkerUsage {
+        self.guest_usage.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn set_guest_usage(&self, usage: WorkerUsage) {
+        *self.guest_usage.lock().unwrap_or_else(|e| e.into_inner()) = usage;
+    }
+
+    /// Handle for stopping a guest run by [`Self::run_guest`] from another thread.
+    pub fn guest_interrupt(&self) -> Arc<GuestInterrupt> {
+        self.interrupt.clone()
     }
 
     /// Unpack the artifact into the sandbox directory.
@@ -59,137 +322,1176 @@ impl ZephirEngine {
         res2?;
 
         let cache_path = Path::new(storage_config.cache.as_deref().unwrap_or(sane_storage_defaults.cache.as_deref().unwrap()));
-        let artifact_cache_path = path::get_artifact_cache(&cache_path);
+        let artifact_cache_path = path::get_artifact_cache(cache_path);
 
         let sandbox_dir_path = Path::new(storage_config.sandbox.as_deref().unwrap_or(sane_storage_defaults.sandbox.as_deref().unwrap()));
-        let sandbox_path = path::get_atomic_sandbox_path(&sandbox_dir_path);
+        let sandbox_path = path::get_atomic_sandbox_path(sandbox_dir_path);
 
+        // Decompressing and copying block, so they run off the runtime; a Ctrl+C
+        // meanwhile is then noticed before the function is started.
+        let package_path = self.config.function.bundle.packagePath.clone();
         if !no_cache && !fs_crud::dir_exists(&artifact_cache_path).await {
-            compress_zstd::decompress_zstd_to_dir(
-                &self.config.function.bundle.packagePath,
-                artifact_cache_path.to_str().expect("Invalid file path"),
-            )?;
+            let (package_path, cache_path) = (package_path.clone(), artifact_cache_path.clone());
+            unblock(move || compress_zstd::decompress_zstd_to_dir(&package_path, cache_path.to_str().expect("Invalid file path"))).await?;
         }
 
-        if no_cache {
-            compress_zstd::decompress_zstd_to_dir(&self.config.function.bundle.packagePath, sandbox_path.to_str().expect("Invalid file path"))?;
+        self.mount_storage_quota(&sandbox_path);
+
+        let (src, dst) = (artifact_cache_path.clone(), sandbox_path.clone());
+        let unpacked = if no_cache {
+            unblock(move || compress_zstd::decompress_zstd_to_dir(&package_path, dst.to_str().expect("Invalid file path"))).await
         } else {
-            fs_crud::copy_dir_recursive(&artifact_cache_path, &sandbox_path)?;
+            unblock(move || fs_crud::copy_dir_recursive(&src, &dst)).await
+        };
+        if let Err(e) = unpacked {
+            let _ = self.cleanup_sandbox(sandbox_path.to_str().expect("Invalid file path"));
+            return Err(e);
         }
 
         Ok(sandbox_path.to_str().unwrap().to_string())
     }
 
-    /// Apply sandbox restrictions: CPU time, memory, and file size.
+    /// Back the sandbox with a tmpfs capped at `resources.storage` bytes and
+    /// `resources.inodes` files. Needs root; otherwise usage is polled while the
+    /// function runs, see [`Self::supervise`].
+    fn mount_storage_quota(&self, sandbox_path: &Path) {
+        let resources = &self.config.function.resources;
+        if resources.storage == 0 || !os_info::has_root_privilege() {
+            return;
+        }
+
+        let mounted = fs::create_dir_all(sandbox_path)
+            .and_then(|()| os_storage::mount_quota(sandbox_path, resources.storage, resources.inodes));
+        if let Err(e) = mounted {
+            warn!("[{}] Could not mount a tmpfs for the storage quota; polling usage instead: {}", self.config.name, e);
+        }
+    }
+
+    /// The cap on a NATIVE function's tasks and the setting it comes from: threads
+    /// count as processes, so it is the lower of `maxProcesses` and `maxThreads`.
+    pub(crate) fn task_limit(&self) -> Option<(u64, &'static str)> {
+        let resources = &self.config.function.resources;
+        if !matches!(self.config.function.bundle.artifactType, config::ArtifactType::NATIVE) {
+            return None;
+        }
+        let processes = resources.maxProcesses.map(|limit| (limit, "maxProcesses"));
+        let threads = resources.maxThreads.map(|limit| (limit, "maxThreads"));
+        match (processes, threads) {
+            (Some(processes), Some(threads)) => Some(if threads.0 < processes.0 { threads } else { processes }),
+            (processes, threads) => processes.or(threads),
+        }
+    }
+
+    /// Limits the function ran into: forks refused by `pids.max`, or the process
+    /// tree seen at its task or open file limit while polling.
+    fn limits_hit(&self, supervised: &Supervised) -> Vec<String> {
+        let resources = &self.config.function.resources;
+        let task_limit = self.task_limit();
+        let mut hit = Vec::new();
+
+        let pids_refused = supervised.cgroup_stats.as_ref().is_some_and(|stats| stats.pids_max_events > 0);
+        if pids_refused {
+            let pids_max = resources.cgroup.pidsMax;
+            hit.push(match task_limit {
+                Some((limit, name)) if pids_max.is_none_or(|pids_max| limit <= pids_max) => name,
+                _ => "pidsMax",
+            });
+        }
+        if let Some((limit, name)) = task_limit
+            && supervised.census.threads.max(supervised.census.uid_threads) >= limit
+            && !hit.contains(&name)
+        {
+            hit.push(name);
+        }
+        if resources.maxOpenFiles.is_some_and(|limit| supervised.census.max_open_files >= limit) {
+            hit.push("maxOpenFiles");
+        }
+
+        hit.into_iter().map(String::from).collect()
+    }
+
+    /// Whether `usage` is over the storage quota.
+    fn over_storage_quota(&self, usage: &os_storage::StorageUsage) -> bool {
+        let resources = &self.config.function.resources;
+        (resources.storage > 0 && usage.bytes > resources.storage)
+            || resources.inodes.is_some_and(|inodes| usage.inodes > inodes)
+    }
+
+    /// Prepare the sandbox directory for the function. The restrictions themselves
+    /// (CPU time, memory, file size, chroot, privilege drop) are applied to the
+    /// spawned child only, see [`Self::sandbox_policy`].
     pub fn sandbox(&self, sandbox_path_str: &str) -> io::Result<()> {
         let sandbox_path = Path::new(sandbox_path_str);
+        if !sandbox_path.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("sandbox directory {} does not exist", sandbox_path.display())));
+        }
+
+        // WASM tmpfs mounts are plain directories, created here so they get the owner
+        // below. Bind targets are preopens of their own, and only checked for symlinks.
+        let native = matches!(self.config.function.bundle.artifactType, config::ArtifactType::NATIVE);
+        if matches!(self.config.function.bundle.artifactType, config::ArtifactType::WASM) {
+            for mount in &self.config.function.mounts {
+                let Ok(relative) = sandbox_relative(&mount.target) else { continue };
+                match mount.mountType {
+                    config::MountType::Tmpfs => drop(os_mount::create_mount_point(sandbox_path, &relative, false)?),
+                    config::MountType::Bind => os_mount::check_mount_point(sandbox_path, &relative)?,
+                }
+            }
+        }
+
+        // The child drops privileges and still has to write into its sandbox. An
+        // invalid runAs is reported by invoke, as a configuration error.
+        if let Ok(RunAs { owner: Some((uid, gid)), .. }) = self.run_as(native) {
+            fs_crud::chown_recursive(sandbox_path, uid, gid)?;
+        }
 
-        os_sandbox::apply_unix_sandbox(
+        Ok(())
+    }
+
+    /// Resolve `runAs`. Root drops to nobody/nogroup unless told otherwise; an
+    /// unprivileged caller can only take other ids inside a user namespace, which
+    /// needs a fresh process and so is limited to NATIVE functions.
+    fn run_as(&self, native: bool) -> Result<RunAs, ZephirInvokationError> {
+        let run_as = &self.config.function.runAs;
+        let is_root = os_info::has_root_privilege();
+        let (current_uid, current_gid) = (nix::unistd::getuid().as_raw(), nix::unistd::getgid().as_raw());
+
+        let (mut uid, mut gid, mut groups) = (run_as.uid, run_as.gid, run_as.groups.clone());
+        if let Some(name) = &run_as.user {
+            let user = nix::unistd::User::from_name(name)
+                .map_err(|e| ZephirInvokationError::Config(format!("failed to look up runAs.user {name}: {e}")))?
+                .ok_or_else(|| ZephirInvokationError::Config(format!("runAs.user {name} does not exist")))?;
+            uid = uid.or(Some(user.uid.as_raw()));
+            gid = gid.or(Some(user.gid.as_raw()));
+            if groups.is_none() {
+                let name = std::ffi::CString::new(name.as_str())
+                    .map_err(|_| ZephirInvokationError::Config(format!("invalid runAs.user {name}")))?;
+                let list = nix::unistd::getgrouplist(&name, user.gid)
+                    .map_err(|e| ZephirInvokationError::Config(format!("failed to look up groups of runAs.user: {e}")))?;
+                groups = Some(list.into_iter().map(|g| g.as_raw()).collect());
+            }
+        }
+
+        let Some(namespace) = &run_as.userNamespace else {
+            if is_root {
+                let (uid, gid) = (uid.unwrap_or(NOBODY), gid.unwrap_or(NOBODY));
+                return Ok(RunAs {
+                    identity: Some(os_sandbox::Identity { uid, gid, groups: Some(groups.unwrap_or_default()) }),
+                    id_maps: None,
+                    owner: Some((uid, gid)),
+                });
+            }
+            if uid.is_some_and(|uid| uid != current_uid)
+                || gid.is_some_and(|gid| gid != current_gid)
+                || groups.as_ref().is_some_and(|groups| !groups.is_empty())
+            {
+                return Err(ZephirInvokationError::Config("runAs needs root or runAs.userNamespace".to_string()));
+            }
+            return Ok(RunAs::default());
+        };
+
+        if !is_root && groups.as_ref().is_some_and(|groups| !groups.is_empty()) {
+            return Err(ZephirInvokationError::Config(
+                "runAs.groups needs root: setgroups is denied in unprivileged user namespaces".to_string(),
+            ));
+        }
+
+        let (uid, gid) = (uid.unwrap_or(current_uid), gid.unwrap_or(current_gid));
+        let to_maps = |entries: &[config::IdMapEntry], inside: u32, outside: u32| {
+            if entries.is_empty() {
+                vec![os_namespace::IdMapping { inside, outside, count: 1 }]
+            } else {
+                entries.iter().map(|e| os_namespace::IdMapping { inside: e.inside, outside: e.outside, count: e.count }).collect()
+            }
+        };
+        let maps = os_namespace::IdMaps {
+            uid_map: to_maps(&namespace.uidMap, uid, current_uid),
+            gid_map: to_maps(&namespace.gidMap, gid, current_gid),
+            deny_setgroups: !is_root,
+        };
+
+        let host_uid = os_namespace::IdMapping::to_host(&maps.uid_map, uid)
+            .ok_or_else(|| ZephirInvokationError::Config(format!("runAs uid {uid} is not in runAs.userNamespace.uidMap")))?;
+        let host_gid = os_namespace::IdMapping::to_host(&maps.gid_map, gid)
+            .ok_or_else(|| ZephirInvokationError::Config(format!("runAs gid {gid} is not in runAs.userNamespace.gidMap")))?;
+        let owner = is_root.then_some((host_uid, host_gid));
+
+        if native {
+            let groups = if is_root { Some(groups.unwrap_or_default()) } else { None };
+            return Ok(RunAs { identity: Some(os_sandbox::Identity { uid, gid, groups }), id_maps: Some(maps), owner });
+        }
+
+        // Workers cannot enter a user namespace; root runs them as the mapped host ids instead.
+        if !is_root {
+            return Err(ZephirInvokationError::Config(
+                "runAs.userNamespace is only supported for NATIVE functions when not running as root".to_string(),
+            ));
+        }
+        let groups = groups
+            .unwrap_or_default()
+            .into_iter()
+            .map(|group| os_namespace::IdMapping::to_host(&maps.gid_map, group)
+                .ok_or_else(|| ZephirInvokationError::Config(format!("runAs group {group} is not in runAs.userNamespace.gidMap"))))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(RunAs {
+            identity: Some(os_sandbox::Identity { uid: host_uid, gid: host_gid, groups: Some(groups) }),
+            id_maps: None,
+            owner,
+        })
+    }
+
+    /// Restrictions applied in the function's process: between fork and exec for
+    /// NATIVE, by the worker itself once the guest is loaded for WASM and Lua.
+    fn sandbox_policy(&self, sandbox_path: &Path, identity: Option<os_sandbox::Identity>) -> io::Result<os_sandbox::SandboxPolicy> {
+        let mut policy = os_sandbox::SandboxPolicy::new(
             os_info::has_root_privilege(),
-            Some(&sandbox_path),
+            Some(sandbox_path),
             self.config.function.resources.cpuLimit,   // CPU time limit (seconds)
-            self.config.function.resources.memory,     // max address space
+            Some(self.config.function.resources.memory), // max address space
             self.config.function.resources.storage     // max file size
         )?;
+        policy.identity = identity;
+        policy.max_open_files = self.config.function.resources.maxOpenFiles;
+        policy.max_stack = self.config.function.resources.maxStack;
 
+        let filesystem = &self.config.function.filesystem;
+        if filesystem.landlock {
+            if os_landlock::abi_version().is_some() {
+                let mut read_paths: Vec<PathBuf> = filesystem.readPaths.iter().map(PathBuf::from).collect();
+                let mut write_paths = Vec::new();
+                // NATIVE mounts live inside the sandbox; WASM reaches bind sources through preopens.
+                if matches!(self.config.function.bundle.artifactType, config::ArtifactType::WASM) {
+                    for mount in self.config.function.mounts.iter().filter(|m| m.mountType == config::MountType::Bind) {
+                        let Some(source) = &mount.source else { continue };
+                        match mount.readOnly {
+                            true => read_paths.push(PathBuf::from(source)),
+                            false => write_paths.push(PathBuf::from(source)),
+                        }
+                    }
+                }
+                policy.landlock.push(os_landlock::build_ruleset(sandbox_path, &read_paths, &write_paths)?);
+            } else {
+                warn!("[{}] Kernel lacks Landlock support; filesystem confinement is not applied", self.config.name);
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// `mounts` for NATIVE functions, with host paths made absolute since the child
+    /// has already changed into the sandbox when it mounts them. Targets stay
+    /// relative to the sandbox, see [`os_mount::create_mount_point`].
+    fn mount_specs(&self, identity: Option<&os_sandbox::Identity>) -> Result<Vec<os_mount::MountSpec>, ZephirInvokationError> {
+        self.config.function.mounts.iter().map(|mount| {
+            let target = sandbox_relative(&mount.target)?;
+            match mount.mountType {
+                config::MountType::Bind => {
+                    let source = mount.source.as_ref().ok_or_else(|| {
+                        ZephirInvokationError::Config(format!("bind mount {} needs a source", mount.target))
+                    })?;
+                    let source = fs::canonicalize(source).map_err(|e| {
+                        ZephirInvokationError::Config(format!("mount source {source}: {e}"))
+                    })?;
+                    Ok(os_mount::MountSpec::Bind { source, target, read_only: mount.readOnly })
+                }
+                config::MountType::Tmpfs => {
+                    let (uid, gid) = identity.map_or_else(
+                        || (nix::unistd::getuid().as_raw(), nix::unistd::getgid().as_raw()),
+                        |identity| (identity.uid, identity.gid),
+                    );
+                    Ok(os_mount::MountSpec::Tmpfs { target, size: mount.size, uid, gid })
+                }
+            }
+        }).collect()
+    }
+
+    /// WASM view of `mounts`: bind sources become extra preopens under the sandbox
+    /// directory, with permissions matching `readOnly`. Call before the sandbox hides them.
+    fn preopen_mounts(&self, builder: &mut WasiCtxBuilder) -> Result<(), ZephirInvokationError> {
+        for mount in &self.config.function.mounts {
+            let guest_path = Path::new(WASM_SANDBOX_DIR).join(sandbox_relative(&mount.target)?);
+            match mount.mountType {
+                config::MountType::Bind => {
+                    let source = mount.source.as_ref().ok_or_else(|| {
+                        ZephirInvokationError::Config(format!("bind mount {} needs a source", mount.target))
+                    })?;
+                    if !Path::new(source).is_dir() {
+                        return Err(ZephirInvokationError::Config(format!("WASM mount source {source} must be a directory")));
+                    }
+                    let (dir_perms, file_perms) = match mount.readOnly {
+                        true => (DirPerms::READ, FilePerms::READ),
+                        false => (DirPerms::all(), FilePerms::all()),
+                    };
+                    builder
+                        .preopened_dir(source, guest_path.to_string_lossy(), dir_perms, file_perms)
+                        .map_err(|e| ZephirInvokationError::Sandbox(io::Error::other(e)))?;
+                }
+                config::MountType::Tmpfs => {
+                    warn!("[{}] tmpfs {} is a plain sandbox directory for WASM functions; its size is not limited", self.config.name, mount.target);
+                }
+            }
+        }
         Ok(())
     }
 
-    /// Invoke a binary or script inside the sandbox and stream stdout/stderr.
-    pub async fn invoke(&self, args: &[&str], sandbox_path: &str) -> Result<(), ZephirInvokationError> {
-        match self.config.function.bundle.artifactType {
-            config::ArtifactType::NATIVE => self.invoke_native(args, sandbox_path).await,
-            config::ArtifactType::WASM => self.invoke_wasm(sandbox_path).await,
-            config::ArtifactType::LUA => self.invoke_lua(sandbox_path).await,
+    /// Network isolation for NATIVE functions: a private network namespace for
+    /// `none` and `loopback`, Landlock port filtering for an allowlist in `host` mode.
+    fn apply_native_network_policy(&self, policy: &mut os_sandbox::SandboxPolicy) -> io::Result<()> {
+        let network = &self.config.function.network;
+
+        if network.mode != config::NetworkMode::Host {
+            let plan = policy.namespaces.get_or_insert_with(Default::default);
+            plan.network = true;
+            plan.loopback = network.mode == config::NetworkMode::Loopback;
+            return Ok(());
+        }
+
+        // Refused by the preflight checks already; never run with an allowlist that is not enforced.
+        if !network.allow.is_empty() {
+            return Err(io::Error::other("network.allow cannot be enforced for NATIVE functions"));
         }
+
+        Ok(())
+    }
+
+    /// WASI socket permissions mirroring the function's network policy.
+    fn configure_wasi_network(&self, builder: &mut WasiCtxBuilder) -> io::Result<()> {
+        let network = &self.config.function.network;
+        let mode = network.mode.clone();
+        let rules = network.allow
+            .iter()
+            .map(|rule| Ok((Cidr::parse(&rule.cidr)?, rule.ports.clone())))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        builder
+            .allow_tcp(mode != config::NetworkMode::None)
+            .allow_udp(mode != config::NetworkMode::None)
+            .allow_ip_name_lookup(mode == config::NetworkMode::Host)
+            .socket_addr_check(move |addr, addr_use| {
+                let allowed = match (&mode, addr_use) {
+                    (config::NetworkMode::None, _) => false,
+                    (config::NetworkMode::Loopback, _) => addr.ip().is_loopback(),
+                    (config::NetworkMode::Host, SocketAddrUse::TcpBind | SocketAddrUse::UdpBind) => true,
+                    (config::NetworkMode::Host, _) => {
+                        rules.is_empty() || rules.iter().any(|(cidr, ports)| {
+                            cidr.contains(addr.ip()) && (ports.is_empty() || ports.contains(&addr.port()))
+                        })
+                    }
+                };
+                Box::pin(async move { allowed })
+            });
+
+        Ok(())
+    }
+
+    /// The function's environment: Zephir's own unless `clearEnv` is set, then
+    /// `envFrom`, then `env`, each overriding the previous.
+    pub fn function_env(&self) -> Result<BTreeMap<String, String>, ZephirInvokationError> {
+        let function = &self.config.function;
+        let mut env = BTreeMap::new();
+
+        if !function.clearEnv {
+            env.extend(std::env::vars());
+        }
+        if let Some(env_file) = &function.envFrom {
+            let vars = dotenv::read_dotenv(Path::new(env_file))
+                .map_err(|e| ZephirInvokationError::Config(format!("envFrom: {e}")))?;
+            env.extend(vars);
+        }
+        env.extend(function.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+        Ok(env)
+    }
+
+    /// Create a per-invocation cgroup with the configured limits. Returns `None`
+    /// (after logging why) when cgroup v2 is unavailable or cannot be set up.
+    fn create_cgroup(&self) -> Option<os_cgroup::Cgroup> {
+        let resources = &self.config.function.resources;
+        let cgroup_config = &resources.cgroup;
+
+        if !cgroup_config.enabled {
+            return None;
+        }
+        if !os_cgroup::is_available() {
+            warn!("[{}] cgroup v2 is not available; relying on rlimits only", self.config.name);
+            return None;
+        }
+
+        let parent = cgroup_config.parent
+            .as_ref()
+            .map(Into::into)
+            .unwrap_or_else(|| os_cgroup::default_parent(os_info::has_root_privilege()));
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_nanos();
+        let name = format!("{}-{}-{}", self.config.name.replace('/', "_"), std::process::id(), nanos);
+
+        let limits = os_cgroup::CgroupLimits {
+            memory_max: Some(resources.memory),
+            cpus: cgroup_config.cpus,
+            pids_max: match (cgroup_config.pidsMax, self.task_limit()) {
+                (Some(pids_max), Some((limit, _))) => Some(pids_max.min(limit)),
+                (pids_max, task_limit) => pids_max.or(task_limit.map(|(limit, _)| limit)),
+            },
+            io_max: cgroup_config.io.iter().map(|io| {
+                let mut line = io.device.clone();
+                for (key, value) in [("rbps", io.rbps), ("wbps", io.wbps), ("riops", io.riops), ("wiops", io.wiops)] {
+                    if let Some(v) = value {
+                        line.push_str(&format!(" {key}={v}"));
+                    }
+                }
+                line
+            }).collect(),
+        };
+
+        let cgroup = match os_cgroup::Cgroup::create(&parent, &name) {
+            Ok(cgroup) => cgroup,
+            Err(e) => {
+                warn!("[{}] Could not create cgroup under {}: {}", self.config.name, parent.display(), e);
+                return None;
+            }
+        };
+        if let Err(e) = cgroup.apply(&limits) {
+            warn!("[{}] Could not apply cgroup limits: {}", self.config.name, e);
+            return None;
+        }
+
+        info!("[{}] Using cgroup {}", self.config.name, cgroup.path().display());
+        Some(cgroup)
+    }
+
+    /// Unpack, sandbox, invoke and clean up, timing each phase.
+    pub async fn run(&self, no_cache: bool, invocation: &Invocation) -> InvocationResult {
+        let started = Instant::now();
+        let unpacked = self.unpack(no_cache).await;
+        let unpack_time = started.elapsed();
+
+        let mut result = match unpacked {
+            Ok(sandbox_path) => self.run_in_sandbox(&sandbox_path, invocation).await,
+            Err(e) => InvocationResult::failed(&self.config.name, FailureKind::Unpack, format!("Failed to unpack sandbox: {e}")),
+        };
+        result.durations.unpack = Some(unpack_time);
+        result
+    }
+
+    /// Prepare an unpacked sandbox, invoke the function in it and remove it afterwards.
+    pub async fn run_in_sandbox(&self, sandbox_path: &str, invocation: &Invocation) -> InvocationResult {
+        let started = Instant::now();
+        let sandboxed = self.sandbox(sandbox_path);
+        let sandbox_time = started.elapsed();
+
+        let mut result = match sandboxed {
+            Ok(()) => self.invoke(invocation, sandbox_path).await,
+            Err(e) => InvocationResult::failed(&self.config.name, FailureKind::Sandbox, format!("Sandbox setup failed: {e}")),
+        };
+        result.durations.sandbox = Some(sandbox_time);
+
+        let started = Instant::now();
+        if let Err(e) = self.cleanup_sandbox(sandbox_path) {
+            error!("[{}] Cleanup failed: {}", self.config.name, e);
+        }
+        result.durations.cleanup = Some(started.elapsed());
+
+        result
+    }
+
+    /// Invoke a binary or script inside the sandbox, streaming and capturing stdout/stderr.
+    ///
+    /// NATIVE functions are spawned directly; WASM and Lua guests run in a
+    /// `zephir worker` process so that they get the same OS-level isolation.
+    pub async fn invoke(&self, invocation: &Invocation, sandbox_path: &str) -> InvocationResult {
+        let mut result = InvocationResult::new(&self.config.name);
+        if *self.cancel.borrow() {
+            let e = ZephirInvokationError::Interrupted("stopped by a shutdown request before the function started".to_string());
+            result.failure = Some(Failure { kind: e.category(), message: e.to_string() });
+            return result;
+        }
+
+        let preflight = doctor_engine::preflight(self);
+        for warning in &preflight.warnings {
+            warn!("[{}] {}", self.config.name, warning);
+        }
+        if !preflight.problems.is_empty() {
+            let message = format!("the host cannot provide the configured isolation: {} (see `zephir doctor`)", preflight.problems.join("; "));
+            result.failure = Some(Failure { kind: FailureKind::Config, message });
+            return result;
+        }
+
+        let started = Instant::now();
+        let outcome = match self.config.function.bundle.artifactType {
+            config::ArtifactType::NATIVE => self.invoke_native(invocation, sandbox_path, &mut result).await,
+            config::ArtifactType::WASM | config::ArtifactType::LUA => self.invoke_worker(invocation, sandbox_path, &mut result).await,
+        };
+
+        result.durations.invoke = Some(started.elapsed());
+        info!("[{}] usage: {}", self.config.name, result.usage);
+        if let Err(e) = outcome {
+            result.failure = Some(Failure { kind: e.category(), message: e.to_string() });
+        }
+        result
+    }
+
+    /// Run the WASM/Lua guest in the current process. Only called inside a `zephir worker`.
+    pub async fn run_guest(&self, sandbox_path: &str, args: &[String], env: &BTreeMap<String, String>) -> Result<(), ZephirInvokationError> {
+        let result = match self.config.function.bundle.artifactType {
+            config::ArtifactType::WASM => self.invoke_wasm(sandbox_path, args, env).await,
+            config::ArtifactType::LUA => {
+                if !args.is_empty() {
+                    warn!("[{}] args are not passed to Lua functions", self.config.name);
+                }
+                self.invoke_lua(sandbox_path, env).await
+            }
+            config::ArtifactType::NATIVE => Err(ZephirInvokationError::Other("NATIVE functions do not run in a worker".to_string())),
+        };
+
+        match result {
+            Err(e) if self.interrupt.is_requested() => Err(ZephirInvokationError::Interrupted(format!("guest stopped: {e}"))),
+            result => result,
+        }
+    }
+
+    /// Make sure an entry shipped in the artifact exists and is executable, so
+    /// that failing to spawn it afterwards is down to the sandbox. Bare names
+    /// (looked up on `PATH`) and absolute paths (possibly behind a mount) are
+    /// left to the spawn.
+    fn check_native_entry(&self, sandbox_dir: &Path) -> Result<(), ZephirInvokationError> {
+        let entry = &self.config.function.app.entry;
+        if !entry.contains('/') || Path::new(entry).is_absolute() {
+            return Ok(());
+        }
+
+        let metadata = fs::metadata(sandbox_dir.join(entry))
+            .map_err(|e| ZephirInvokationError::Config(format!("entry {entry} cannot be run: {e}")))?;
+        if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+            return Err(ZephirInvokationError::Config(format!("entry {entry} is not an executable file")));
+        }
+        Ok(())
     }
 
     /// Invoke a native binary.
-    pub async fn invoke_native(&self, args: &[&str], sandbox_path: &str) -> Result<(), ZephirInvokationError> {
+    pub async fn invoke_native(&self, invocation: &Invocation, sandbox_path: &str, result: &mut InvocationResult) -> Result<(), ZephirInvokationError> {
         let sandbox_dir = Path::new(sandbox_path);
+        self.check_native_entry(sandbox_dir)?;
 
-        let mut child = process::Command::new(&self.config.function.app.entry)
-            .args(args)
+        let mut command = process::Command::new(&self.config.function.app.entry);
+        command
+            .args(&invocation.args)
             .current_dir(sandbox_dir)
+            .env_clear()
+            .envs(self.function_env()?);
+
+        let run_as = self.run_as(true)?;
+        let mut policy = self.sandbox_policy(sandbox_dir, run_as.identity).map_err(ZephirInvokationError::Sandbox)?;
+        self.apply_native_network_policy(&mut policy).map_err(ZephirInvokationError::Sandbox)?;
+
+        let mounts = self.mount_specs(policy.identity.as_ref())?
+            .iter()
+            .map(|spec| os_mount::prepare(sandbox_dir, spec))
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(ZephirInvokationError::Sandbox)?;
+        if !mounts.is_empty() {
+            policy.namespaces.get_or_insert_with(Default::default).mounts = mounts;
+        }
+
+        // Unprivileged callers also need a user namespace to own a network or mount namespace.
+        let id_maps = run_as.id_maps.or_else(|| {
+            let needs_user_namespace = !policy.is_root
+                && policy.namespaces.as_ref().is_some_and(|plan| plan.network || !plan.mounts.is_empty());
+            needs_user_namespace.then(os_namespace::IdMaps::identity)
+        });
+        // RLIMIT_NPROC counts every process of the real uid, Zephir's own included,
+        // so it is only safe once the function has a uid (or user namespace) to itself.
+        if let Some((limit, name)) = self.task_limit() {
+            let own_uid = policy.identity.as_ref().is_none_or(|identity| identity.uid == nix::unistd::getuid().as_raw());
+            if own_uid && id_maps.is_none() {
+                warn!("[{}] {} is only enforced through the cgroup while the function runs as Zephir's own uid", self.config.name, name);
+            } else {
+                policy.max_tasks = Some(limit);
+            }
+        }
+
+        let id_map_writer = match id_maps {
+            Some(maps) => {
+                let (writer, sync) = os_namespace::IdMapWriter::start(maps).map_err(ZephirInvokationError::Sandbox)?;
+                policy.namespaces.get_or_insert_with(Default::default).user = Some(sync);
+                Some(writer)
+            }
+            None => None,
+        };
+
+        let supervised = self.supervise(command, Some(policy), None, invocation.payload.clone(), sandbox_dir).await;
+        drop(id_map_writer);
+        let mut supervised = supervised?;
+        self.record(&mut supervised, result);
+        self.check_limits(&supervised)?;
+
+        if !supervised.status.success() {
+            return Err(ZephirInvokationError::Exited(supervised.status));
+        }
+
+        Ok(())
+    }
+
+    /// Run the WASM/Lua guest in a `zephir worker` child process, which sandboxes
+    /// itself once the guest is loaded and reports back over a control socket.
+    /// The payload is piped to the worker's stdin, which it hands to the guest.
+    async fn invoke_worker(&self, invocation: &Invocation, sandbox_path: &str, result: &mut InvocationResult) -> Result<(), ZephirInvokationError> {
+        let (parent_end, worker_end) = StdUnixStream::pair()?;
+
+        let mut command = process::Command::new(std::env::current_exe()?);
+        // The worker gets nothing from our environment; the guest's is sent in the request.
+        command
+            .arg("worker")
+            .arg("--fd")
+            .arg(WORKER_FD.to_string())
+            .env_clear();
+
+        let request = WorkerRequest {
+            config: self.config.clone(),
+            sandbox_path: sandbox_path.to_string(),
+            args: invocation.args.clone(),
+            env: self.function_env()?,
+        };
+        let mut request_line = serde_json::to_vec(&request).map_err(io::Error::from)?;
+        request_line.push(b'\n');
+
+        let worker = WorkerSocket { parent_end, worker_end, request_line };
+        let mut supervised = self.supervise(command, None, Some(worker), invocation.payload.clone(), Path::new(sandbox_path)).await?;
+        self.record(&mut supervised, result);
+
+        let outcome = self.check_limits(&supervised).and_then(|()| match supervised.worker_result {
+            Some(None) => Ok(()),
+            Some(Some(error)) => Err(error.into()),
+            None => Err(ZephirInvokationError::Other(format!(
+                "Worker exited with {} before reporting a result", supervised.status
+            ))),
+        });
+        // The worker's own exit code only stands for the guest's when the guest exited.
+        if let Err(e) = &outcome {
+            result.exit_code = match e {
+                ZephirInvokationError::Exited(status) => status.code(),
+                _ => None,
+            };
+        }
+        outcome
+    }
+
+    /// Move what was observed about the process into the invocation result.
+    fn record(&self, supervised: &mut Supervised, result: &mut InvocationResult) {
+        result.exit_code = supervised.status.code();
+        result.signal = supervised.status.signal();
+        result.stdout = std::mem::take(&mut supervised.stdout);
+        result.stderr = std::mem::take(&mut supervised.stderr);
+        result.timeline = std::mem::take(&mut supervised.timeline);
+
+        if let Some(rusage) = &supervised.rusage {
+            result.usage.user_time_us = Some(rusage.user_time_us);
+            result.usage.system_time_us = Some(rusage.system_time_us);
+            result.usage.max_rss_kib = Some(rusage.max_rss_kib);
+            result.usage.minor_faults = Some(rusage.minor_faults);
+            result.usage.major_faults = Some(rusage.major_faults);
+            result.usage.block_input = Some(rusage.block_input);
+            result.usage.block_output = Some(rusage.block_output);
+            result.usage.voluntary_context_switches = Some(rusage.voluntary_switches);
+            result.usage.involuntary_context_switches = Some(rusage.involuntary_switches);
+        }
+        if let Some(usage) = &supervised.worker_usage {
+            result.usage.fuel_consumed = usage.fuel_consumed;
+            result.usage.wasm_memory_bytes = usage.wasm_memory_bytes;
+            result.usage.lua_instructions = usage.lua_instructions;
+            result.usage.lua_peak_heap_bytes = usage.lua_peak_heap_bytes;
+        }
+        if let Some(stats) = &supervised.cgroup_stats {
+            result.usage.user_time_us = result.usage.user_time_us.or(stats.cpu_user_usec);
+            result.usage.system_time_us = result.usage.system_time_us.or(stats.cpu_system_usec);
+            result.usage.memory_peak_bytes = stats.memory_peak;
+            result.usage.oom_kills = Some(stats.oom_kills);
+        }
+        result.limits_hit = self.limits_hit(supervised);
+        if !result.limits_hit.is_empty() {
+            warn!("[{}] Function ran into its limits: {}", self.config.name, result.limits_hit.join(", "));
+        }
+        if let Some(usage) = &supervised.storage_usage {
+            result.usage.storage_bytes = Some(usage.bytes);
+            result.usage.storage_inodes = Some(usage.inodes);
+        }
+    }
+
+    /// Spawn `command` in its own session and cgroup, feed `payload` to its stdin,
+    /// stream its stdout/stderr through the logger into the captures and, for
+    /// workers, handle protocol messages until every stream closes.
+    ///
+    /// Everything the function started is killed and reaped when this returns,
+    /// fails, or its future is dropped (e.g. on Ctrl+C).
+    async fn supervise(
+        &self,
+        mut command: process::Command,
+        mut policy: Option<os_sandbox::SandboxPolicy>,
+        worker: Option<WorkerSocket>,
+        payload: Option<Vec<u8>>,
+        sandbox_path: &Path,
+    ) -> Result<Supervised, ZephirInvokationError> {
+        if let Err(e) = os_process::become_subreaper() {
+            warn!("[{}] Could not become a child subreaper; orphaned processes may escape cleanup: {}", self.config.name, e);
+        }
+
+        let cgroup = self.create_cgroup();
+        // memory.max caps what the tree actually uses. RLIMIT_AS also counts address
+        // space a runtime merely reserves, and RLIMIT_NPROC every process of a uid
+        // that others (nobody, say) may share, so both are only fallbacks for when
+        // there is no cgroup; its pids.max carries the task limit.
+        if cgroup.is_some() && let Some(policy) = &mut policy {
+            policy.memory_limit = None;
+            policy.max_tasks = None;
+        }
+        let nproc_uid = policy.as_ref().and_then(|policy| policy.nproc_uid());
+        let cgroup_procs = cgroup.as_ref().map(|cg| cg.open_procs()).transpose()?;
+
+        command
+            .stdin(if payload.is_some() { Stdio::piped() } else { Stdio::null() })
             .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
+            .stderr(Stdio::piped());
 
-        let stdout = child.stdout.take().expect("Child did not have stdout");
-        let stderr = child.stderr.take().expect("Child did not have stderr");
+        let sandboxed = policy.is_some();
+        let procs_fd = cgroup_procs.as_ref().map(|procs| procs.as_raw_fd());
+        let worker_fd = worker.as_ref().map(|w| w.worker_end.as_raw_fd());
+        // SAFETY: the hook only issues raw syscalls on data prepared before the fork;
+        // the fds it touches stay open until spawn returns.
+        unsafe {
+            command.pre_exec(move || {
+                // Own session and process group, so the whole tree can be signalled at once.
+                os_process::start_session()?;
+                // Join the cgroup while still privileged, then lock the process down.
+                if let Some(fd) = procs_fd {
+                    os_cgroup::join_from_child(fd)?;
+                }
+                if let Some(fd) = worker_fd {
+                    os_sandbox::inherit_fd(fd, WORKER_FD)?;
+                }
+                match &mut policy {
+                    Some(policy) => os_sandbox::apply_unix_sandbox(policy),
+                    None => Ok(()),
+                }
+            });
+        }
+
+        // Registered before the spawn, so the function cannot exit unnoticed.
+        let mut child_exited = signal(SignalKind::child())?;
 
-        let mut stdout_reader = BufReader::new(stdout).lines();
-        let mut stderr_reader = BufReader::new(stderr).lines();
+        // Once the entry is known to be there, a failed spawn is a pre_exec step
+        // (namespaces, cgroup, rlimits, chroot, Landlock, identity) going wrong.
+        let mut child = command.spawn().map_err(|e| match sandboxed {
+            true => ZephirInvokationError::Sandbox(e),
+            false => ZephirInvokationError::Io(e),
+        })?;
+        drop(cgroup_procs);
+        let pid = child.id().expect("Child has no pid before it was awaited");
+        let mut tree = os_process::ProcessTree::new(pid, cgroup).inspect_err(|_| {
+            // Nothing else can see the function yet; this is all it can have started.
+            os_process::signal_group(pid, Signal::SIGKILL);
+        })?;
 
-        loop {
+        let mut control = match worker {
+            Some(WorkerSocket { parent_end, worker_end, request_line }) => {
+                // Close our copy of the worker's end so EOF means the worker is gone.
+                drop(worker_end);
+                parent_end.set_nonblocking(true)?;
+                let mut stream = UnixStream::from_std(parent_end)?;
+                stream.write_all(&request_line).await?;
+                Some(BufReader::new(stream).lines())
+            }
+            None => None,
+        };
+
+        if let (Some(mut stdin), Some(payload)) = (child.stdin.take(), payload) {
+            // Written concurrently with draining stdout, so a large payload cannot deadlock.
+            let name = self.config.name.clone();
+            tokio::spawn(async move {
+                if let Err(e) = stdin.write_all(&payload).await
+                    && e.kind() != io::ErrorKind::BrokenPipe
+                {
+                    warn!("[{}] Failed to write the payload to stdin: {}", name, e);
+                }
+            });
+        }
+
+        let mut stdout = child.stdout.take().expect("Child did not have stdout");
+        let mut stderr = child.stderr.take().expect("Child did not have stderr");
+        let (mut stdout_buf, mut stderr_buf) = (vec![0; READ_CHUNK], vec![0; READ_CHUNK]);
+
+        let resources = &self.config.function.resources;
+        let deadline = resources.timeout.map(|secs| Instant::now() + Duration::from_secs(secs));
+        let mut kill_at = None;
+        let mut drain_until = None;
+        let mut status = None;
+        let mut rusage = os_process::ProcessUsage::default();
+
+        let mut supervised = Supervised::default();
+        let (cache_dir, _) = self.config.storage_dirs();
+        let spill_dir = path::get_output_spill(&cache_dir, sandbox_path);
+        let mut output = OutputPump::new(&self.config.name, &self.config.function.output, &spill_dir);
+        let (mut stdout_open, mut stderr_open, mut control_open) = (true, true, control.is_some());
+
+        let session = tree.session();
+        let poll_storage = !os_storage::is_quota_mount(sandbox_path)
+            && (resources.storage > 0 || resources.inodes.is_some());
+        let poll_tree = self.task_limit().is_some() || resources.maxOpenFiles.is_some();
+        let mut poll = tokio::time::interval(POLL_INTERVAL);
+        let mut track = tokio::time::interval(TRACK_INTERVAL);
+        let mut cancel = self.cancel.subscribe();
+
+        while stdout_open || stderr_open || control_open || status.is_none() {
             tokio::select! {
-                line = stdout_reader.next_line() => match line? {
-                    Some(l) => info!("[{}_info] {}", self.config.name, l),
-                    None => break,
+                read = stdout.read(&mut stdout_buf), if stdout_open => match read? {
+                    0 => stdout_open = false,
+                    n => output.push(OutputStream::Stdout, &stdout_buf[..n])?,
+                },
+                read = stderr.read(&mut stderr_buf), if stderr_open => match read? {
+                    0 => stderr_open = false,
+                    n => output.push(OutputStream::Stderr, &stderr_buf[..n])?,
+                },
+                line = async { control.as_mut().expect("control socket").next_line().await }, if control_open => match line? {
+                    Some(l) => self.handle_worker_message(&l, &mut supervised),
+                    None => control_open = false,
+                },
+                // Reaped with wait4 rather than through `child`, for the function's own
+                // rusage; any other child exiting wakes this up too.
+                _ = child_exited.recv(), if status.is_none() => {
+                    if let Some((exit_status, usage)) = tree.try_wait()? {
+                        (status, rusage) = (Some(exit_status), usage);
+                        // The function is done; anything it left running would keep its pipes open.
+                        tree.kill();
+                        drain_until = Some(Instant::now() + DRAIN_TIMEOUT);
+                    }
+                },
+                _ = sleep_until_some(drain_until) => {
+                    warn!("[{}] Output was still open {:?} after the function exited; killing what holds it", self.config.name, DRAIN_TIMEOUT);
+                    (stdout_open, stderr_open, control_open) = (false, false, false);
+                    tree.kill();
+                    drain_until = None;
                 },
-                line = stderr_reader.next_line() => match line? {
-                    Some(l) => error!("[{}_error] {}", self.config.name, l),
-                    None => break,
+                _ = sleep_until_some(deadline), if !supervised.timed_out => {
+                    warn!("[{}] Wall-clock timeout reached; sending SIGTERM", self.config.name);
+                    supervised.timed_out = true;
+                    tree.signal(Signal::SIGTERM);
+                    // A shutdown request may already have started the grace period.
+                    kill_at.get_or_insert(Instant::now() + Duration::from_secs(resources.gracePeriod));
+                },
+                _ = cancel.wait_for(|cancelled| *cancelled), if !supervised.cancelled && status.is_none() => {
+                    warn!("[{}] Shutdown requested; sending SIGTERM", self.config.name);
+                    supervised.cancelled = true;
+                    tree.signal(Signal::SIGTERM);
+                    kill_at.get_or_insert(Instant::now() + Duration::from_secs(resources.gracePeriod));
+                },
+                _ = sleep_until_some(kill_at) => {
+                    warn!("[{}] Still running after the grace period; sending SIGKILL", self.config.name);
+                    tree.kill();
+                    kill_at = None;
+                },
+                _ = track.tick(), if status.is_none() => tree.track(),
+                _ = poll.tick(), if (poll_storage || poll_tree) && status.is_none() && !supervised.storage_exceeded => {
+                    let path = sandbox_path.to_path_buf();
+                    let measured = tokio::task::spawn_blocking(move || {
+                        let usage = poll_storage.then(|| os_storage::usage(&path));
+                        let census = poll_tree.then(|| os_process::census(session, nproc_uid));
+                        (usage, census)
+                    }).await;
+                    let (usage, census) = match measured {
+                        Ok(measured) => measured,
+                        Err(e) => {
+                            warn!("[{}] Polling the function failed: {}", self.config.name, e);
+                            continue;
+                        }
+                    };
+
+                    if let Some(census) = census {
+                        supervised.census.raise_to(&census);
+                    }
+                    match usage {
+                        Some(Ok(usage)) if self.over_storage_quota(&usage) => {
+                            warn!("[{}] Storage quota exceeded ({} bytes, {} inodes); killing the function", self.config.name, usage.bytes, usage.inodes);
+                            supervised.storage_exceeded = true;
+                            tree.kill();
+                        }
+                        Some(Err(e)) => warn!("[{}] Failed to measure sandbox storage: {}", self.config.name, e),
+                        _ => {}
+                    }
                 },
             }
         }
 
-        let status = child.wait().await?;
-        if !status.success() {
-            return Err(ZephirInvokationError::Other(format!("Native process exited with {}", status)));
+        supervised.status = status.expect("loop exits only after the child was reaped");
+        match os_storage::usage(sandbox_path) {
+            Ok(usage) => supervised.storage_usage = Some(usage),
+            Err(e) => warn!("[{}] Failed to measure sandbox storage: {}", self.config.name, e),
         }
+        (supervised.stdout, supervised.stderr, supervised.timeline) = output.finish();
 
-        Ok(())
+        if let Some(cgroup) = tree.cgroup() {
+            let stats = cgroup.stats();
+            info!(
+                "[{}] cgroup usage: peak memory {} bytes, cpu {}us (user {}us, system {}us), oom events {}, oom kills {}",
+                self.config.name,
+                stats.memory_peak.map_or("n/a".to_string(), |v| v.to_string()),
+                stats.cpu_usage_usec.unwrap_or(0),
+                stats.cpu_user_usec.unwrap_or(0),
+                stats.cpu_system_usec.unwrap_or(0),
+                stats.oom_events,
+                stats.oom_kills,
+            );
+            supervised.cgroup_stats = Some(stats);
+        }
+
+        // Whatever is left is reaped here, and what it used counts too.
+        rusage.add(&tree.shutdown().await);
+        supervised.rusage = Some(rusage);
+
+        Ok(supervised)
     }
 
-    /// Invoke a WASM module using wasmtime + WASI.
-    pub async fn invoke_wasm(&self, sandbox_path: &str) -> Result<(), ZephirInvokationError> {
-        let engine = Engine::default();
-        let module = Module::from_file(&engine, &self.config.function.app.entry)?;
+    fn handle_worker_message(&self, line: &str, supervised: &mut Supervised) {
+        match serde_json::from_str::<WorkerMessage>(line) {
+            Ok(WorkerMessage::Log { level, message }) => {
+                log::log!(level.parse().unwrap_or(log::Level::Info), "{}", message);
+            }
+            Ok(WorkerMessage::Usage(usage)) => supervised.worker_usage = Some(usage),
+            Ok(WorkerMessage::Result { error }) => supervised.worker_result = Some(error),
+            Err(e) => warn!("[{}] Ignoring malformed worker message: {}", self.config.name, e),
+        }
+    }
 
-        let wasi = WasiCtxBuilder::new()
-            .inherit_stdio()
+    fn check_limits(&self, supervised: &Supervised) -> Result<(), ZephirInvokationError> {
+        if supervised.cancelled {
+            return Err(ZephirInvokationError::Interrupted("stopped by a shutdown request".to_string()));
+        }
+        if supervised.timed_out {
+            let timeout = self.config.function.resources.timeout.unwrap_or_default();
+            return Err(ZephirInvokationError::Timeout(Duration::from_secs(timeout)));
+        }
+
+        // Killed by polling, or failed on a quota tmpfs with no room left.
+        let storage_full = supervised.storage_usage.as_ref().is_some_and(|usage| usage.full);
+        if supervised.storage_exceeded || (!supervised.status.success() && storage_full) {
+            let resources = &self.config.function.resources;
+            return Err(ZephirInvokationError::ResourceLimit(match resources.inodes {
+                Some(inodes) => format!("storage quota of {} bytes / {} inodes reached", resources.storage, inodes),
+                None => format!("storage quota of {} bytes reached", resources.storage),
+            }));
+        }
+
+        let limits_hit = self.limits_hit(supervised);
+        if !supervised.status.success() && !limits_hit.is_empty() {
+            return Err(ZephirInvokationError::ResourceLimit(format!("{} reached", limits_hit.join(", "))));
+        }
+
+        let oom_killed = supervised.cgroup_stats.as_ref().is_some_and(|stats| stats.oom_kills > 0);
+        if !supervised.status.success() && oom_killed {
+            return Err(ZephirInvokationError::MemoryLimit(format!(
+                "process was OOM-killed by the cgroup (memory.max = {} bytes)",
+                self.config.function.resources.memory
+            )));
+        }
+
+        match supervised.status.signal() {
+            Some(libc::SIGXCPU) => Err(ZephirInvokationError::ResourceLimit(format!(
+                "CPU time limit of {}s reached", self.config.function.resources.cpuLimit
+            ))),
+            Some(libc::SIGXFSZ) => Err(ZephirInvokationError::ResourceLimit(format!(
+                "file size limit of {} bytes reached", self.config.function.resources.storage
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sandbox the current process (a `zephir worker`) and return the sandbox
+    /// path as seen from inside it.
+    fn enter_sandbox(&self, sandbox_path: &str, limit_address_space: bool) -> Result<String, ZephirInvokationError> {
+        let run_as = self.run_as(false)?;
+        let mut policy = self.sandbox_policy(Path::new(sandbox_path), run_as.identity).map_err(ZephirInvokationError::Sandbox)?;
+        if !limit_address_space {
+            policy.memory_limit = None;
+        }
+
+        os_sandbox::apply_unix_sandbox(&mut policy).map_err(ZephirInvokationError::Sandbox)?;
+
+        if policy.chroots() {
+            Ok("/".to_string())
+        } else {
+            Ok(sandbox_path.to_string())
+        }
+    }
+
+    /// Invoke a WASM module (WASI preview 1) or component (WASI preview 2) using
+    /// wasmtime. Runs inside a `zephir worker`.
+    pub async fn invoke_wasm(&self, sandbox_path: &str, args: &[String], env: &BTreeMap<String, String>) -> Result<(), ZephirInvokationError> {
+        let fuel = wasm_limits::fuel_budget(&self.config.function.resources);
+        let engine = wasm_guest::engine(fuel.is_some()).map_err(|e| ZephirInvokationError::Other(format!("wasmtime setup failed: {e:#}")))?;
+        let bytes = fs::read(Path::new(sandbox_path).join(&self.config.function.app.entry))?;
+        let (cache_dir, _) = self.config.storage_dirs();
+        let guest = CompileCache::new(&self.config.name, path::get_wasm_cache(&cache_dir))
+            .load(&engine, &bytes)
+            .map_err(ZephirInvokationError::WasmLoad)?;
+
+        let mut builder = WasiCtxBuilder::new();
+        self.preopen_mounts(&mut builder)?;
+
+        // The payload arrives on our stdin (empty when there is none). It is read
+        // up front and served to the guest from memory, so reading stdin never
+        // blocks the guest on the worker's own file descriptor.
+        let mut payload = Vec::new();
+        io::stdin().read_to_end(&mut payload)?;
+
+        // wasmtime reserves far more address space than it uses, so RLIMIT_AS is left to the cgroup.
+        let sandbox_path = self.enter_sandbox(sandbox_path, false)?;
+
+        let env: Vec<(&String, &String)> = env.iter().collect();
+        // Our stdout and stderr are the pipes `supervise` drains, so the guest's
+        // output is logged, captured and capped like that of a native function.
+        builder
+            .stdin(MemoryInputPipe::new(payload))
+            .inherit_stdout()
+            .inherit_stderr()
+            .arg(&self.config.function.app.entry)
+            .args(args)
+            .envs(&env)
             .preopened_dir(
-                Path::new(sandbox_path),
-                "/sandbox",
+                Path::new(&sandbox_path),
+                WASM_SANDBOX_DIR,
                 DirPerms::all(),
                 FilePerms::all(),
-            )?
-            .build();
-
-        let mut store = Store::new(&engine, wasi);
+            )
+            // WASI has no working directory; wasi-libc resolves relative paths
+            // against a `.` preopen, which makes the sandbox the guest's cwd.
+            .and_then(|builder| builder.preopened_dir(Path::new(&sandbox_path), ".", DirPerms::all(), FilePerms::all()))
+            .map_err(|e| ZephirInvokationError::Sandbox(io::Error::other(e)))?;
+        self.configure_wasi_network(&mut builder).map_err(ZephirInvokationError::Sandbox)?;
 
-        if self.config.function.resources.cpuLimit > 0 {
-            store.set_fuel(self.config.function.resources.cpuLimit * 1_000_000)?;
-        }
+        let limits = GuestLimits::new(&self.config.function.resources);
+        let timeout = self.config.function.resources.timeout.map(Duration::from_secs);
+        let setup = StoreSetup {
+            fuel,
+            deadline: timeout.map(|timeout| std::time::Instant::now() + timeout),
+            interrupt: self.interrupt.clone(),
+        };
+        self.interrupt.watch_wasm(&engine);
+        let ticker = wasm_limits::EpochTicker::start(&engine)?;
 
-        let instance = Instance::new(&mut store, &module, &[])?;
-        let start_func = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+        info!("[{}] Starting WASM {}", self.config.name, guest.kind());
+        let GuestRun { outcome, started, usage, limit_hit } = guest
+            .run(&engine, builder, limits, &setup)
+            .await
+            .map_err(|e| ZephirInvokationError::Other(format!("wasmtime setup failed: {e:#}")))?;
+        drop(ticker);
+        self.set_guest_usage(usage);
 
-        info!("[{}] Starting WASM module", self.config.name);
-        start_func.call(&mut store, ())?;
-        info!("[{}] WASM module finished", self.config.name);
+        // proc_exit unwinds the guest with an I32Exit error, which is an exit status rather than a trap.
+        let code = match outcome {
+            Ok(()) => 0,
+            Err(e) => match e.downcast_ref::<wasmtime_wasi::I32Exit>() {
+                Some(exit) => exit.0,
+                None if e.downcast_ref::<Trap>() == Some(&Trap::OutOfFuel) => {
+                    return Err(ZephirInvokationError::ResourceLimit(format!(
+                        "CPU limit reached: the guest used up its {} units of fuel", fuel.unwrap_or_default()
+                    )));
+                }
+                // An interruption is reported as such by `run_guest`; otherwise the deadline passed.
+                None if e.downcast_ref::<Trap>() == Some(&Trap::Interrupt) && !self.interrupt.is_requested() => {
+                    return Err(ZephirInvokationError::Timeout(timeout.unwrap_or_default()));
+                }
+                None => match limit_hit {
+                    Some(hit) => return Err(hit.into()),
+                    None if !started && !e.is::<Trap>() => return Err(ZephirInvokationError::WasmLoad(e)),
+                    None => return Err(ZephirInvokationError::Wasm(e)),
+                },
+            },
+        };
+        if code != 0 {
+            info!("[{}] WASM module exited with code {}", self.config.name, code);
+            // A guest that gives up after being refused memory is reported as having run out of it.
+            if let Some(hit) = limit_hit {
+                return Err(hit.into());
+            }
+            // Like exit(2), only the low 8 bits make it into the status, but a
+            // failure never turns into success by losing the rest.
+            let status = match code & 0xff {
+                0 => 1,
+                low => low,
+            };
+            return Err(ZephirInvokationError::Exited(ExitStatus::from_raw(status << 8)));
+        }
+        info!("[{}] WASM guest finished", self.config.name);
 
         Ok(())
     }
 
-    /// Invoke a Lua script.
-    pub async fn invoke_lua(&self, sandbox_path: &str) -> Result<(), ZephirInvokationError> {
+    /// Invoke a Lua script. Runs inside a `zephir worker`.
+    pub async fn invoke_lua(&self, sandbox_path: &str, env: &BTreeMap<String, String>) -> Result<(), ZephirInvokationError> {
         let script_path = Path::new(sandbox_path).join(&self.config.function.app.entry);
         let script = fs::read_to_string(script_path)?;
+        if !self.config.function.mounts.is_empty() {
+            warn!("[{}] mounts are not applied to Lua functions", self.config.name);
+        }
 
+        // The payload arrives on our stdin (empty when there is none).
+        let mut payload = Vec::new();
+        io::stdin().read_to_end(&mut payload)?;
+
+        let sandbox_path = self.enter_sandbox(sandbox_path, true)?;
 
         let lua = Lua::new_with(
             StdLib::ALL_SAFE,
             LuaOptions::default(),
         )?;
 
+        let interrupt = self.interrupt.clone();
+        let instructions = Arc::new(AtomicU64::new(0));
+        let peak_heap = Arc::new(AtomicU64::new(0));
+        let (hook_instructions, hook_peak_heap) = (instructions.clone(), peak_heap.clone());
+        lua.set_hook(HookTriggers::new().every_nth_instruction(LUA_INTERRUPT_CHECK_INTERVAL), move |lua, _| {
+            hook_instructions.fetch_add(LUA_INTERRUPT_CHECK_INTERVAL as u64, Ordering::Relaxed);
+            hook_peak_heap.fetch_max(lua.used_memory() as u64, Ordering::Relaxed);
+            if interrupt.is_requested() {
+                return Err(mlua::Error::runtime("interrupted"));
+            }
+            Ok(VmState::Continue)
+        })?;
+        // LuaJIT does not run count hooks inside compiled traces, so a hot loop
+        // could only be stopped by SIGKILL. Interpreting is much slower, so only
+        // scripts with a timeout pay for it.
+        if self.config.function.resources.timeout.is_some() {
+            lua.load("if jit then jit.off() end").exec()?;
+        }
+
         let globals = lua.globals();
-        globals.set("sandbox_path", sandbox_path)?;
+        globals.set("sandbox_path", sandbox_path.as_str())?;
+
+        // `env` table, and `os.getenv` answering from it rather than the worker's own environment.
+        let env_table = lua.create_table_from(env.iter().map(|(k, v)| (k.as_str(), v.as_str())))?;
+        if let Ok(os) = globals.get::<mlua::Table>("os") {
+            let lookup = env_table.clone();
+            os.set("getenv", lua.create_function(move |_, name: String| lookup.get::<Option<String>>(name))?)?;
+        }
+        globals.set("env", env_table)?;
+
+        // `event` is the decoded payload when it is JSON and the raw bytes otherwise.
+        if !payload.is_empty() {
+            let raw = lua.create_string(&payload)?;
+            let event = match serde_json::from_slice::<serde_json::Value>(&payload) {
+                Ok(json) => lua.to_value(&json)?,
+                Err(_) => mlua::Value::String(raw.clone()),
+            };
+            globals.set("event", event)?;
+            globals.set("event_raw", raw)?;
+        }
         globals.set(
             "print",
+            // Through stdout, so Lua output is logged and captured like any other function's.
             lua.create_function(|_, msg: String| {
-                info!("[Lua] {}", msg);
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(msg.as_bytes())?;
+                stdout.write_all(b"\n")?;
+                stdout.flush()?;
                 Ok(())
             })?,
         )?;
@@ -197,7 +1499,14 @@ impl ZephirEngine {
         info!("[{}] Starting Lua script", self.config.name);
 
         let chunk = lua.load(&script).set_name("user_script");
-        chunk.exec()?;
+        let outcome = chunk.exec();
+        peak_heap.fetch_max(lua.used_memory() as u64, Ordering::Relaxed);
+        self.set_guest_usage(WorkerUsage {
+            lua_instructions: Some(instructions.load(Ordering::Relaxed)),
+            lua_peak_heap_bytes: Some(peak_heap.load(Ordering::Relaxed)),
+            ..WorkerUsage::default()
+        });
+        outcome?;
 
         info!("[{}] Lua script finished", self.config.name);
 
@@ -207,9 +1516,182 @@ impl ZephirEngine {
     /// Clean up the sandbox directory after execution.
     pub fn cleanup_sandbox(&self, sandbox_path: &str) -> io::Result<()> {
         let path = Path::new(sandbox_path);
+        if os_storage::is_quota_mount(path) {
+            os_storage::unmount(path)?;
+        }
         if path.exists() {
             fs::remove_dir_all(path)?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An engine without a cgroup, so the function's processes are only found
+    /// through its session and its descendants.
+    fn engine(timeout: Option<u64>, grace_period: u64) -> ZephirEngine {
+        let mut config = config::ZephirConfig::sane_defaults();
+        config.name = "test".to_string();
+        config.function.resources.timeout = timeout;
+        config.function.resources.gracePeriod = grace_period;
+        config.function.resources.cgroup.enabled = false;
+        ZephirEngine::new(config)
+    }
+
+    /// Supervise `script` run by `/bin/sh` in an empty directory of its own.
+    async fn run(engine: &ZephirEngine, name: &str, script: &str, worker: Option<WorkerSocket>) -> Supervised {
+        let sandbox = std::env::temp_dir().join(format!("zephir-supervise-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&sandbox);
+        fs::create_dir_all(&sandbox).unwrap();
+
+        let mut command = process::Command::new("/bin/sh");
+        command.arg("-c").arg(script);
+        let supervised = engine.supervise(command, None, worker, None, &sandbox).await.unwrap();
+        fs::remove_dir_all(&sandbox).unwrap();
+        supervised
+    }
+
+    fn text(output: &CapturedOutput) -> String {
+        String::from_utf8(output.data.clone().expect("output was spilled")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_timeout_sends_sigterm_first() {
+        let started = Instant::now();
+        let supervised = run(&engine(Some(1), 5), "sigterm", "trap 'echo terminated; exit 7' TERM; while :; do sleep 0.1; done", None).await;
+
+        assert!(supervised.timed_out);
+        assert_eq!(supervised.status.code(), Some(7));
+        assert_eq!(text(&supervised.stdout), "terminated\n");
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn sigkill_follows_once_the_grace_period_is_over() {
+        let started = Instant::now();
+        let supervised = run(&engine(Some(1), 1), "sigkill", "trap '' TERM; while :; do sleep 0.1; done", None).await;
+
+        assert!(supervised.timed_out);
+        assert_eq!(supervised.status.signal(), Some(libc::SIGKILL));
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_secs(2) && elapsed < Duration::from_secs(4), "took {elapsed:?}");
+    }
+
+    fn worker_socket() -> WorkerSocket {
+        let (parent_end, worker_end) = StdUnixStream::pair().unwrap();
+        WorkerSocket { parent_end, worker_end, request_line: b"{\"job\":1}\n".to_vec() }
+    }
+
+    #[tokio::test]
+    async fn workers_get_the_request_and_report_over_json_lines() {
+        let script = r#"
+            read -r request <&3
+            echo "$request"
+            echo 'not json' >&3
+            echo '{"type":"Log","level":"info","message":"hello"}' >&3
+            echo '{"type":"Usage","fuel_consumed":5}' >&3
+            echo '{"type":"Result","error":null}' >&3
+        "#;
+        let supervised = run(&engine(None, 1), "worker", script, Some(worker_socket())).await;
+
+        assert_eq!(text(&supervised.stdout), "{\"job\":1}\n");
+        assert_eq!(supervised.worker_usage.and_then(|usage| usage.fuel_consumed), Some(5));
+        assert!(matches!(supervised.worker_result, Some(None)));
+    }
+
+    #[tokio::test]
+    async fn a_guest_timeout_reported_by_the_worker_keeps_its_deadline() {
+        let script = r#"
+            read -r request <&3
+            echo '{"type":"Result","error":{"kind":"Timeout","message":"","timeout_ms":1500}}' >&3
+        "#;
+        let supervised = run(&engine(None, 1), "worker-timeout", script, Some(worker_socket())).await;
+
+        let error = supervised.worker_result.flatten().expect("the worker reported no error");
+        let error = ZephirInvokationError::from(error);
+        assert!(matches!(error, ZephirInvokationError::Timeout(timeout) if timeout == Duration::from_millis(1500)));
+        assert_eq!(error.category(), FailureKind::Timeout);
+    }
+
+    /// Pids the script printed, one per line.
+    fn pids(output: &CapturedOutput) -> Vec<i32> {
+        text(output).lines().map(|line| line.parse().expect("not a pid")).collect()
+    }
+
+    /// Whether `pid` is gone for good, i.e. it was killed and reaped.
+    fn is_gone(pid: i32) -> bool {
+        nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), None) == Err(nix::errno::Errno::ESRCH)
+    }
+
+    #[tokio::test]
+    async fn a_timeout_kills_and_reaps_what_left_the_session() {
+        // One escapee stays a child of the function, the other is orphaned and
+        // ends up with Zephir as its subreaper.
+        let script = "setsid sleep 30 & echo $!; (setsid sleep 30 & echo $!; sleep 0.3); sleep 30";
+        let supervised = run(&engine(Some(1), 1), "escapees", script, None).await;
+
+        assert!(supervised.timed_out);
+        let escapees = pids(&supervised.stdout);
+        assert_eq!(escapees.len(), 2);
+        assert!(escapees.into_iter().all(is_gone));
+    }
+
+    #[tokio::test]
+    async fn cancelling_kills_the_tree_after_the_grace_period() {
+        let engine = engine(None, 1);
+        let script = "trap '' TERM; setsid sleep 30 & echo $!; sleep 30";
+        let (supervised, ()) = tokio::join!(run(&engine, "cancel", script, None), async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            engine.cancel();
+        });
+
+        assert!(supervised.cancelled);
+        assert_eq!(supervised.status.signal(), Some(libc::SIGKILL));
+        assert!(pids(&supervised.stdout).into_iter().all(is_gone));
+    }
+
+    #[tokio::test]
+    async fn other_children_of_zephir_are_left_alone() {
+        let mut bystander = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        let supervised = run(&engine(None, 1), "bystander", "sleep 30 & echo $!", None).await;
+
+        assert!(supervised.status.success());
+        assert!(pids(&supervised.stdout).into_iter().all(is_gone));
+        assert!(bystander.try_wait().unwrap().is_none(), "a process the function did not start was reaped");
+        bystander.kill().unwrap();
+        bystander.wait().unwrap();
+    }
+
+    #[tokio::test]
+    async fn stderr_is_drained_after_stdout_closes() {
+        // The last burst is still in the pipe when the function exits.
+        let script = "exec 1>&-; for i in 1 2 3; do echo $i >&2; sleep 0.1; done; exec head -c 60000 /dev/zero >&2";
+        let supervised = run(&engine(None, 1), "stdout-closed", script, None).await;
+
+        assert_eq!(text(&supervised.stdout), "");
+        assert_eq!(supervised.stderr.size, 60006);
+        assert!(text(&supervised.stderr).starts_with("1\n2\n3\n"));
+    }
+
+    #[tokio::test]
+    async fn stdout_is_drained_after_stderr_closes() {
+        let script = "exec 2>&-; for i in 1 2 3; do echo $i; sleep 0.1; done; exec head -c 60000 /dev/zero";
+        let supervised = run(&engine(None, 1), "stderr-closed", script, None).await;
+
+        assert_eq!(supervised.stdout.size, 60006);
+        assert!(text(&supervised.stdout).starts_with("1\n2\n3\n"));
+        assert_eq!(text(&supervised.stderr), "");
+    }
+
+    #[tokio::test]
+    async fn a_leftover_holding_the_output_open_does_not_keep_the_run_going() {
+        let started = Instant::now();
+        let supervised = run(&engine(None, 1), "held-open", "sleep 30 & echo done", None).await;
+
+        assert_eq!(text(&supervised.stdout), "done\n");
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
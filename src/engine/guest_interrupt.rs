@@ -0,0 +1,32 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Lets another thread stop a WASM or Lua guest running in a `zephir worker`.
+#[derive(Debug, Default)]
+pub struct GuestInterrupt {
+    requested: AtomicBool,
+    wasm_engine: Mutex<Option<wasmtime::Engine>>,
+}
+
+impl GuestInterrupt {
+    pub fn interrupt(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        if let Some(engine) = self.wasm_engine.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            engine.increment_epoch();
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Register the engine whose epoch is bumped on interruption. The engine
+    /// must have epoch interruption enabled.
+    pub fn watch_wasm(&self, engine: &wasmtime::Engine) {
+        *self.wasm_engine.lock().unwrap_or_else(|e| e.into_inner()) = Some(engine.clone());
+        // An interruption that arrived before registration still has to stop the guest.
+        if self.is_requested() {
+            engine.increment_epoch();
+        }
+    }
+}
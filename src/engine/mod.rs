@@ -1,2 +1,10 @@
+pub mod doctor_engine;
 pub mod exec_engine;
+pub mod guest_interrupt;
+pub mod output_capture;
+pub mod output_pump;
 pub mod pack_engine;
+pub mod wasm_cache;
+pub mod wasm_guest;
+pub mod wasm_limits;
+pub mod worker_engine;
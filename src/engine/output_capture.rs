@@ -0,0 +1,210 @@
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::models::result::CapturedOutput;
+
+/// Output kept in memory before it is spilled to a file.
+pub const INLINE_LIMIT: usize = 64 * 1024;
+
+/// Collects one output stream of a function, in memory while it is small and
+/// in a file under the invocation's spill directory once it grows past
+/// [`INLINE_LIMIT`].
+pub struct OutputCapture {
+    /// File name prefix, e.g. `my-function.stdout`.
+    label: String,
+    spill_dir: PathBuf,
+    data: Vec<u8>,
+    spill: Option<(PathBuf, File)>,
+    size: u64,
+    /// Bytes to keep; anything past it is only counted.
+    limit: u64,
+    kept: u64,
+    truncated: bool,
+}
+
+impl OutputCapture {
+    pub fn new(label: String, limit: u64, spill_dir: &Path) -> Self {
+        let spill_dir = spill_dir.to_path_buf();
+        Self { label, spill_dir, data: Vec::new(), spill: None, size: 0, limit, kept: 0, truncated: false }
+    }
+
+    /// Capture `bytes`, up to the limit. Returns how many of them were kept; once the
+    /// limit is reached a truncation marker is added and later output is dropped.
+    pub fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.size += bytes.len() as u64;
+        if self.truncated {
+            return Ok(0);
+        }
+
+        let room = self.limit - self.kept;
+        let keep = bytes.len().min(usize::try_from(room).unwrap_or(usize::MAX));
+        self.append(&bytes[..keep])?;
+        self.kept += keep as u64;
+
+        if keep < bytes.len() {
+            self.truncated = true;
+            let marker = format!("\n[zephir: {} truncated after {} bytes]\n", self.label, self.kept);
+            self.append(marker.as_bytes())?;
+        }
+        Ok(keep)
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    fn append(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.spill.is_none() && self.data.len() + bytes.len() > INLINE_LIMIT {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_nanos();
+            DirBuilder::new().recursive(true).mode(0o700).create(&self.spill_dir)?;
+            let path = self.spill_dir.join(format!(
+                "zephir-{}-{}-{}",
+                self.label.replace('/', "_"),
+                std::process::id(),
+                nanos
+            ));
+            // The name is predictable, so never follow or reuse what is already there.
+            let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+            file.write_all(&self.data)?;
+            self.data = Vec::new();
+            self.spill = Some((path, file));
+        }
+
+        match &mut self.spill {
+            Some((_, file)) => file.write_all(bytes),
+            None => {
+                self.data.extend_from_slice(bytes);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn finish(self) -> CapturedOutput {
+        let truncated = self.truncated;
+        match self.spill {
+            Some((path, _)) => CapturedOutput { size: self.size, data: None, path: Some(path), truncated },
+            None => CapturedOutput { size: self.size, data: Some(self.data), path: None, truncated },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn new_capture(label: &str, limit: u64) -> OutputCapture {
+        OutputCapture::new(label.to_string(), limit, &std::env::temp_dir().join("zephir-capture-test"))
+    }
+
+    fn spilled(captured: &CapturedOutput) -> Vec<u8> {
+        let path = captured.path.as_ref().expect("output was not spilled");
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn small_output_stays_in_memory() {
+        let mut capture = new_capture("test.stdout", u64::MAX);
+        assert_eq!(capture.write(b"hello ").unwrap(), 6);
+        assert_eq!(capture.write(b"world").unwrap(), 5);
+
+        let captured = capture.finish();
+        assert_eq!(captured.data.as_deref(), Some(&b"hello world"[..]));
+        assert_eq!((captured.size, captured.path, captured.truncated), (11, None, false));
+    }
+
+    #[test]
+    fn output_up_to_the_inline_limit_is_not_spilled() {
+        let mut capture = new_capture("test.stdout", u64::MAX);
+        capture.write(&vec![b'a'; INLINE_LIMIT - 1]).unwrap();
+        capture.write(b"b").unwrap();
+
+        let captured = capture.finish();
+        assert_eq!(captured.data.map(|data| data.len()), Some(INLINE_LIMIT));
+        assert!(captured.path.is_none());
+    }
+
+    #[test]
+    fn output_past_the_inline_limit_moves_to_a_private_file() {
+        let mut capture = new_capture("test/spill.stdout", u64::MAX);
+        let first: Vec<u8> = (0..INLINE_LIMIT - 10).map(|i| i as u8).collect();
+        capture.write(&first).unwrap();
+        capture.write(&[1; 20]).unwrap();
+        capture.write(b"tail").unwrap();
+
+        let captured = capture.finish();
+        assert!(captured.data.is_none());
+        assert_eq!(captured.size, INLINE_LIMIT as u64 + 14);
+        let path = captured.path.clone().unwrap();
+        assert_eq!(path.parent(), Some(std::env::temp_dir().join("zephir-capture-test").as_path()));
+        assert!(path.file_name().unwrap().to_str().unwrap().starts_with("zephir-test_spill.stdout-"));
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let mut expected = first;
+        expected.extend_from_slice(&[1; 20]);
+        expected.extend_from_slice(b"tail");
+        assert_eq!(spilled(&captured), expected);
+    }
+
+    #[test]
+    fn output_past_the_cap_is_counted_but_dropped() {
+        let mut capture = new_capture("test.stdout", 10);
+        assert_eq!(capture.write(b"123456").unwrap(), 6);
+        assert!(!capture.is_truncated());
+        assert_eq!(capture.write(b"789abc").unwrap(), 4);
+        assert!(capture.is_truncated());
+        assert_eq!(capture.write(b"more").unwrap(), 0);
+
+        let captured = capture.finish();
+        assert_eq!(captured.size, 16);
+        assert!(captured.truncated);
+        assert_eq!(
+            String::from_utf8(captured.data.unwrap()).unwrap(),
+            "123456789a\n[zephir: test.stdout truncated after 10 bytes]\n"
+        );
+    }
+
+    #[test]
+    fn output_exactly_at_the_cap_is_not_truncated() {
+        let mut capture = new_capture("test.stdout", 4);
+        assert_eq!(capture.write(b"1234").unwrap(), 4);
+        assert!(!capture.is_truncated());
+        assert_eq!(capture.write(b"5").unwrap(), 0);
+        assert!(capture.is_truncated());
+    }
+
+    #[test]
+    fn a_zero_cap_keeps_only_the_marker() {
+        let mut capture = new_capture("test.stderr", 0);
+        assert_eq!(capture.write(b"x").unwrap(), 0);
+
+        let captured = capture.finish();
+        assert_eq!((captured.size, captured.truncated), (1, true));
+        assert_eq!(captured.data.as_deref(), Some(&b"\n[zephir: test.stderr truncated after 0 bytes]\n"[..]));
+    }
+
+    #[test]
+    fn a_cap_above_the_inline_limit_truncates_the_spilled_file() {
+        let cap = INLINE_LIMIT as u64 + 100;
+        let mut capture = new_capture("test.stdout", cap);
+        for _ in 0..3 {
+            capture.write(&vec![b'x'; INLINE_LIMIT / 2]).unwrap();
+        }
+
+        let captured = capture.finish();
+        assert!(captured.truncated);
+        assert_eq!(captured.size, 3 * (INLINE_LIMIT / 2) as u64);
+        let bytes = spilled(&captured);
+        let marker = format!("\n[zephir: test.stdout truncated after {cap} bytes]\n");
+        assert_eq!(bytes.len() as u64, cap + marker.len() as u64);
+        assert!(bytes.ends_with(marker.as_bytes()));
+    }
+}
@@ -0,0 +1,216 @@
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use log::{error, info, warn};
+
+use crate::engine::output_capture::OutputCapture;
+use crate::models::config;
+use crate::models::result::{CapturedOutput, OutputSegment, OutputStream, OutputTimeline};
+
+/// Segments recorded before the timeline stops growing.
+const MAX_SEGMENTS: usize = 10_000;
+
+struct StreamState {
+    capture: OutputCapture,
+    /// Bytes of a line that has not ended yet.
+    line: Vec<u8>,
+}
+
+/// Takes raw chunks of a function's stdout and stderr as they arrive: captures
+/// them byte for byte, logs them line by line and records the order they came in.
+pub struct OutputPump {
+    name: String,
+    started: Instant,
+    max_line_length: usize,
+    stdout: StreamState,
+    stderr: StreamState,
+    timeline: OutputTimeline,
+}
+
+impl OutputPump {
+    pub fn new(name: &str, output: &config::OutputConfig, spill_dir: &Path) -> Self {
+        let stream = |suffix: &str, limit: u64| StreamState {
+            capture: OutputCapture::new(format!("{name}.{suffix}"), limit, spill_dir),
+            line: Vec::new(),
+        };
+        Self {
+            name: name.to_string(),
+            started: Instant::now(),
+            max_line_length: output.maxLineLength.max(1),
+            stdout: stream("stdout", output.maxStdout),
+            stderr: stream("stderr", output.maxStderr),
+            timeline: OutputTimeline::default(),
+        }
+    }
+
+    pub fn push(&mut self, stream: OutputStream, bytes: &[u8]) -> io::Result<()> {
+        let at = self.started.elapsed();
+        let state = match stream {
+            OutputStream::Stdout => &mut self.stdout,
+            OutputStream::Stderr => &mut self.stderr,
+        };
+        let was_truncated = state.capture.is_truncated();
+        let kept = state.capture.write(bytes)?;
+        state.line.extend_from_slice(&bytes[..kept]);
+        while let Some(end) = state.line.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = state.line.drain(..=end).collect();
+            log_line(&self.name, stream, &line);
+        }
+        while state.line.len() >= self.max_line_length {
+            let piece: Vec<u8> = state.line.drain(..self.max_line_length).collect();
+            log_line(&self.name, stream, &piece);
+        }
+        if state.capture.is_truncated() && !was_truncated {
+            let line = std::mem::take(&mut state.line);
+            if !line.is_empty() {
+                log_line(&self.name, stream, &line);
+            }
+            let label = match stream {
+                OutputStream::Stdout => "stdout",
+                OutputStream::Stderr => "stderr",
+            };
+            warn!("[{}] {} reached its cap; dropping the rest", self.name, label);
+        }
+
+        if kept > 0 {
+            self.record(stream, at, kept as u64);
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, stream: OutputStream, at: Duration, size: u64) {
+        let timeline = &mut self.timeline;
+        if !timeline.complete {
+            return;
+        }
+        if let Some(last) = timeline.segments.last_mut() && last.stream == stream {
+            last.size += size;
+        } else if timeline.segments.len() >= MAX_SEGMENTS {
+            timeline.complete = false;
+        } else {
+            timeline.segments.push(OutputSegment { stream, at, size });
+        }
+    }
+
+    /// Log what is left of unterminated lines and hand over the captures.
+    pub fn finish(mut self) -> (CapturedOutput, CapturedOutput, OutputTimeline) {
+        for (stream, state) in [(OutputStream::Stdout, &mut self.stdout), (OutputStream::Stderr, &mut self.stderr)] {
+            if !state.line.is_empty() {
+                log_line(&self.name, stream, &state.line);
+            }
+        }
+        (self.stdout.capture.finish(), self.stderr.capture.finish(), self.timeline)
+    }
+}
+
+fn log_line(name: &str, stream: OutputStream, line: &[u8]) {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let text = String::from_utf8_lossy(line);
+    match stream {
+        OutputStream::Stdout => info!("[{name}_info] {text}"),
+        OutputStream::Stderr => error!("[{name}_error] {text}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pump(max_stdout: u64, max_line_length: usize) -> OutputPump {
+        let output = config::OutputConfig { maxStdout: max_stdout, maxStderr: u64::MAX, maxLineLength: max_line_length };
+        OutputPump::new("test", &output, &std::env::temp_dir().join("zephir-pump-test"))
+    }
+
+    fn segments(timeline: &OutputTimeline) -> Vec<(OutputStream, u64)> {
+        timeline.segments.iter().map(|segment| (segment.stream, segment.size)).collect()
+    }
+
+    #[test]
+    fn partial_lines_wait_for_their_end() {
+        let mut pump = pump(u64::MAX, 1024);
+        pump.push(OutputStream::Stdout, b"hel").unwrap();
+        assert_eq!(pump.stdout.line, b"hel");
+        pump.push(OutputStream::Stdout, b"lo\nwor").unwrap();
+        assert_eq!(pump.stdout.line, b"wor");
+        pump.push(OutputStream::Stdout, b"ld\n\nnext").unwrap();
+        assert_eq!(pump.stdout.line, b"next");
+
+        let (stdout, _, timeline) = pump.finish();
+        assert_eq!(stdout.data.as_deref(), Some(&b"hello\nworld\n\nnext"[..]));
+        assert_eq!(segments(&timeline), vec![(OutputStream::Stdout, 17)]);
+    }
+
+    #[test]
+    fn long_lines_are_logged_in_pieces() {
+        let mut pump = pump(u64::MAX, 4);
+        pump.push(OutputStream::Stderr, b"abcdefghij").unwrap();
+        assert_eq!(pump.stderr.line, b"ij");
+        pump.push(OutputStream::Stderr, b"kl\n").unwrap();
+        assert!(pump.stderr.line.is_empty());
+    }
+
+    #[test]
+    fn the_timeline_follows_the_streams() {
+        let mut pump = pump(u64::MAX, 1024);
+        pump.push(OutputStream::Stdout, b"a\n").unwrap();
+        pump.push(OutputStream::Stdout, b"b").unwrap();
+        pump.push(OutputStream::Stderr, b"oops\n").unwrap();
+        pump.push(OutputStream::Stdout, b"\n").unwrap();
+
+        let (stdout, stderr, timeline) = pump.finish();
+        assert_eq!(stdout.data.as_deref(), Some(&b"a\nb\n"[..]));
+        assert_eq!(stderr.data.as_deref(), Some(&b"oops\n"[..]));
+        assert_eq!(
+            segments(&timeline),
+            vec![(OutputStream::Stdout, 3), (OutputStream::Stderr, 5), (OutputStream::Stdout, 1)]
+        );
+        assert!(timeline.complete);
+        assert!(timeline.segments.windows(2).all(|pair| pair[0].at <= pair[1].at));
+    }
+
+    #[test]
+    fn the_timeline_only_counts_kept_bytes() {
+        let mut pump = pump(5, 1024);
+        pump.push(OutputStream::Stdout, b"abc\ndefgh").unwrap();
+        // The partial line is flushed once the cap is reached.
+        assert!(pump.stdout.line.is_empty());
+        pump.push(OutputStream::Stdout, b"dropped\n").unwrap();
+        pump.push(OutputStream::Stderr, b"err").unwrap();
+
+        let (stdout, _, timeline) = pump.finish();
+        assert!(stdout.truncated);
+        assert_eq!(stdout.size, 17);
+        assert_eq!(segments(&timeline), vec![(OutputStream::Stdout, 5), (OutputStream::Stderr, 3)]);
+    }
+
+    #[test]
+    fn the_timeline_stops_after_too_many_segments() {
+        let mut pump = pump(u64::MAX, 1024);
+        for i in 0..=MAX_SEGMENTS {
+            let stream = if i % 2 == 0 { OutputStream::Stdout } else { OutputStream::Stderr };
+            pump.push(stream, b"x").unwrap();
+        }
+
+        let (_, _, timeline) = pump.finish();
+        assert_eq!(timeline.segments.len(), MAX_SEGMENTS);
+        assert!(!timeline.complete);
+    }
+
+    #[test]
+    fn chunks_across_the_inline_limit_end_up_in_one_file() {
+        let mut pump = pump(u64::MAX, 1024);
+        let chunk = vec![b'x'; 40 * 1024];
+        pump.push(OutputStream::Stdout, &chunk).unwrap();
+        pump.push(OutputStream::Stdout, b"\n").unwrap();
+        pump.push(OutputStream::Stdout, &chunk).unwrap();
+
+        let (stdout, _, timeline) = pump.finish();
+        let path = stdout.path.expect("stdout was not spilled");
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes.len(), 2 * chunk.len() + 1);
+        assert_eq!(bytes[chunk.len()], b'\n');
+        assert_eq!(segments(&timeline), vec![(OutputStream::Stdout, bytes.len() as u64)]);
+    }
+}
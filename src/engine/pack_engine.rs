@@ -1,8 +1,9 @@
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use thiserror::Error;
 use std::io;
 
 use crate::models::config;
+use crate::models::result::FailureKind;
 use crate::utils::fs::yaml;
 use crate::compress::compress_zstd;
 
@@ -15,6 +16,15 @@ pub enum PackageError {
     Yaml(#[from] yaml::ParseError),
 }
 
+impl PackageError {
+    pub fn category(&self) -> FailureKind {
+        match self {
+            Self::Io(_) => FailureKind::Io,
+            Self::Yaml(e) => e.category(),
+        }
+    }
+}
+
 pub struct PackageEngine {
     directory_path: PathBuf,
     config_path: Option<PathBuf>,
@@ -24,10 +34,7 @@ impl PackageEngine {
     pub fn new(directory_path: &str, config_path: Option<&str>) -> Self {
         let dir_path = PathBuf::from(directory_path);
 
-        let config_path_var = match config_path {
-            Some(path) => Some(PathBuf::from(path)),
-            None => None,
-        };
+        let config_path_var = config_path.map(PathBuf::from);
 
         Self {
             directory_path: dir_path,
@@ -47,8 +54,6 @@ impl PackageEngine {
             yaml::write_yaml_to_file::<config::ZephirConfig>(default_path.to_str().expect("Invalid file-path."), &default_config).await?;
         }
          
-        let config = self.config_path.as_ref().unwrap_or_else(|| &default_path);
-        
         compress_zstd::compress_dir_to_zstd(self.directory_path
                 .to_str()
                 .expect("Invalid file-path."),
@@ -0,0 +1,141 @@
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use wasmtime::component::Component;
+use wasmtime::{Engine, Module, Precompiled};
+
+use crate::engine::wasm_guest::{self, WasmGuest};
+
+/// Bytes of the SHA-256 digest each entry starts with.
+const CHECKSUM_LEN: usize = 32;
+
+/// Compiled WASM guests, kept between invocations so a module is only compiled
+/// once. Entries are named after the SHA-256 of the module and the
+/// [`engine_key`], so a new wasmtime or a different fuel/epoch setup never
+/// finds an old entry.
+pub struct CompileCache {
+    name: String,
+    dir: PathBuf,
+}
+
+/// Whether only this user can change `path`: loading native code from a file
+/// someone else can write would hand them the worker.
+fn is_private(path: &Path) -> io::Result<bool> {
+    let metadata = fs::metadata(path)?;
+    Ok(metadata.uid() == nix::unistd::geteuid().as_raw() && metadata.mode() & 0o022 == 0)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Feeds what is hashed into a SHA-256, which unlike `DefaultHasher` gives the
+/// same result in every build.
+struct Sha256Hasher(Sha256);
+
+impl Hasher for Sha256Hasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        let digest = self.0.clone().finalize();
+        u64::from_le_bytes(digest[..8].try_into().expect("digest is 32 bytes"))
+    }
+}
+
+/// Identifies what code compiled by `engine` is compatible with: the wasmtime
+/// version and every engine setting that affects it.
+pub fn engine_key(engine: &Engine) -> String {
+    let mut hasher = Sha256Hasher(Sha256::new());
+    engine.precompile_compatibility_hash().hash(&mut hasher);
+    hex(&hasher.0.finalize()[..8])
+}
+
+impl CompileCache {
+    pub fn new(name: &str, dir: PathBuf) -> Self {
+        Self { name: name.to_string(), dir }
+    }
+
+    fn entry_path(&self, engine: &Engine, bytes: &[u8]) -> PathBuf {
+        self.dir.join(format!("{}-{}.cwasm", hex(&Sha256::digest(bytes)), engine_key(engine)))
+    }
+
+    /// The compiled guest for `bytes`: from the cache when it holds a usable
+    /// entry, compiled (and cached) otherwise.
+    pub fn load(&self, engine: &Engine, bytes: &[u8]) -> wasmtime::Result<WasmGuest> {
+        let path = self.entry_path(engine, bytes);
+        match self.read(engine, &path, wasm_guest::is_component(bytes)) {
+            Ok(Some(guest)) => {
+                info!("[{}] Using compiled WASM from {}", self.name, path.display());
+                return Ok(guest);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("[{}] Recompiling; cached {} is unusable: {:#}", self.name, path.display(), e),
+        }
+
+        let guest = WasmGuest::new(engine, bytes)?;
+        let serialized = match &guest {
+            WasmGuest::Module(module) => module.serialize(),
+            WasmGuest::Component(component) => component.serialize(),
+        };
+        match serialized {
+            Ok(serialized) => {
+                if let Err(e) = self.write(&path, &serialized) {
+                    warn!("[{}] Could not cache compiled WASM at {}: {}", self.name, path.display(), e);
+                }
+            }
+            Err(e) => warn!("[{}] Could not serialize compiled WASM: {:#}", self.name, e),
+        }
+        Ok(guest)
+    }
+
+    fn read(&self, engine: &Engine, path: &Path, component: bool) -> wasmtime::Result<Option<WasmGuest>> {
+        let entry = match fs::read(path) {
+            Ok(entry) => entry,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if !is_private(path)? || !is_private(&self.dir)? {
+            anyhow::bail!("it or {} can be written by other users", self.dir.display());
+        }
+        if entry.len() < CHECKSUM_LEN {
+            anyhow::bail!("entry is truncated");
+        }
+        let (checksum, serialized) = entry.split_at(CHECKSUM_LEN);
+        if Sha256::digest(serialized).as_slice() != checksum {
+            anyhow::bail!("checksum mismatch");
+        }
+
+        // Deserializing loads native code as is. The checksum catches torn or
+        // damaged files, and wasmtime refuses artifacts built for another version
+        // or configuration.
+        match (Engine::detect_precompiled(serialized), component) {
+            (Some(Precompiled::Module), false) => Ok(Some(WasmGuest::Module(unsafe { Module::deserialize(engine, serialized)? }))),
+            (Some(Precompiled::Component), true) => Ok(Some(WasmGuest::Component(unsafe { Component::deserialize(engine, serialized)? }))),
+            _ => anyhow::bail!("entry is not a compiled {}", if component { "component" } else { "module" }),
+        }
+    }
+
+    /// Write the entry under a temporary name and rename it into place, so a
+    /// concurrent reader sees either nothing or the whole entry.
+    fn write(&self, path: &Path, serialized: &[u8]) -> io::Result<()> {
+        DirBuilder::new().recursive(true).mode(0o700).create(&self.dir)?;
+        let temp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+        let written = (|| {
+            let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp_path)?;
+            file.write_all(&Sha256::digest(serialized))?;
+            file.write_all(serialized)?;
+            file.sync_all()?;
+            fs::rename(&temp_path, path)
+        })();
+        if written.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        written
+    }
+}
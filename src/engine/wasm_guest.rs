@@ -0,0 +1,179 @@
+use std::sync::Arc;
+use std::time::Instant;
+use wasmtime::component::{self, Component, ResourceTable};
+use wasmtime::{Config, Engine, Linker, Memory, Module, Store, Trap, UpdateDeadline};
+use wasmtime_wasi::p1::WasiP1Ctx;
+use wasmtime_wasi::p2::bindings::Command;
+use wasmtime_wasi::{I32Exit, WasiCtx, WasiCtxBuilder, WasiCtxView, WasiView};
+
+use crate::engine::guest_interrupt::GuestInterrupt;
+use crate::engine::wasm_limits::{GuestLimits, LimitHit};
+use crate::models::protocol::WorkerUsage;
+
+/// A compiled guest: a core module exporting `_start` (WASI preview 1), or a
+/// component implementing `wasi:cli/run` (WASI preview 2).
+pub enum WasmGuest {
+    Module(Module),
+    Component(Component),
+}
+
+/// Store data of a WASM guest.
+pub struct WasmState<W> {
+    pub wasi: W,
+    pub limits: GuestLimits,
+}
+
+/// WASI state of a component.
+pub struct ComponentWasi {
+    ctx: WasiCtx,
+    table: ResourceTable,
+}
+
+impl WasiView for WasmState<ComponentWasi> {
+    fn ctx(&mut self) -> WasiCtxView<'_> {
+        WasiCtxView { ctx: &mut self.wasi.ctx, table: &mut self.wasi.table }
+    }
+}
+
+/// The engine guests run on: async, stopped through epochs, and metering fuel
+/// when there is a fuel budget.
+pub fn engine(fuel: bool) -> wasmtime::Result<Engine> {
+    let mut config = Config::new();
+    config.epoch_interruption(true);
+    config.async_support(true);
+    config.consume_fuel(fuel);
+    Engine::new(&config)
+}
+
+/// Whether `bytes` (binary or text format) are a component rather than a core module.
+pub fn is_component(bytes: &[u8]) -> bool {
+    if let Some(header) = bytes.strip_prefix(b"\0asm") {
+        // The two bytes after the version are the layer: 0 for modules, 1 for components.
+        return header.get(2..4) == Some(&[1, 0]);
+    }
+    let text = String::from_utf8_lossy(bytes);
+    let code = text
+        .lines()
+        .map(|line| line.split(";;").next().unwrap_or_default())
+        .collect::<String>();
+    code.trim_start().strip_prefix('(').is_some_and(|rest| rest.trim_start().starts_with("component"))
+}
+
+impl WasmGuest {
+    pub fn new(engine: &Engine, bytes: &[u8]) -> wasmtime::Result<Self> {
+        match is_component(bytes) {
+            true => Ok(Self::Component(Component::new(engine, bytes)?)),
+            false => Ok(Self::Module(Module::new(engine, bytes)?)),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Module(_) => "module",
+            Self::Component(_) => "component",
+        }
+    }
+
+    /// Instantiate the guest and run it to completion. The guest yields back to
+    /// the runtime at every epoch tick.
+    pub async fn run(self, engine: &Engine, mut wasi: WasiCtxBuilder, limits: GuestLimits, setup: &StoreSetup) -> wasmtime::Result<GuestRun> {
+        match self {
+            Self::Module(module) => {
+                let mut linker: Linker<WasmState<WasiP1Ctx>> = Linker::new(engine);
+                wasmtime_wasi::p1::add_to_linker_async(&mut linker, |state| &mut state.wasi)?;
+                let mut store = setup.store(engine, wasi.build_p1(), limits)?;
+
+                let (mut memory_bytes, mut started) = (None, false);
+                let instance = match store.data_mut().limits.admit(Some(module.resources_required())) {
+                    Ok(()) => linker.instantiate_async(&mut store, &module).await,
+                    Err(e) => Err(e),
+                };
+                let outcome = match instance {
+                    Ok(instance) => {
+                        let outcome = match instance.get_typed_func::<(), ()>(&mut store, "_start") {
+                            Ok(start_func) => {
+                                started = true;
+                                start_func.call_async(&mut store, ()).await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        // Linear memory never shrinks, so its final size is its peak.
+                        let memories: Vec<Memory> = instance.exports(&mut store).filter_map(|export| export.into_memory()).collect();
+                        memory_bytes = Some(memories.iter().map(|memory| memory.data_size(&store) as u64).sum());
+                        outcome
+                    }
+                    Err(e) => Err(e),
+                };
+                Ok(setup.finish(store, outcome, memory_bytes, started))
+            }
+            Self::Component(component) => {
+                let mut linker: component::Linker<WasmState<ComponentWasi>> = component::Linker::new(engine);
+                wasmtime_wasi::p2::add_to_linker_async(&mut linker)?;
+                let mut store = setup.store(engine, ComponentWasi { ctx: wasi.build(), table: ResourceTable::new() }, limits)?;
+
+                // `run` returning an error is the component's way of exiting with a failure.
+                let command = match store.data_mut().limits.admit(component.resources_required()) {
+                    Ok(()) => Command::instantiate_async(&mut store, &component, &linker).await,
+                    Err(e) => Err(e),
+                };
+                let started = command.is_ok();
+                let outcome = match command {
+                    Ok(command) => command.wasi_cli_run().call_run(&mut store).await,
+                    Err(e) => Err(e),
+                };
+                let outcome = outcome.and_then(|result| result.map_err(|()| I32Exit(1).into()));
+                Ok(setup.finish(store, outcome, None, started))
+            }
+        }
+    }
+}
+
+/// Fuel and deadline every guest store is set up with.
+pub struct StoreSetup {
+    pub fuel: Option<u64>,
+    pub deadline: Option<Instant>,
+    pub interrupt: Arc<GuestInterrupt>,
+}
+
+/// How a guest run ended, with what it used.
+pub struct GuestRun {
+    pub outcome: wasmtime::Result<()>,
+    /// The entry point was called. Before that, an error that is not a trap
+    /// means the guest could not be linked or instantiated, or has no entry point.
+    pub started: bool,
+    pub usage: WorkerUsage,
+    pub limit_hit: Option<LimitHit>,
+}
+
+impl StoreSetup {
+    fn store<W: 'static>(&self, engine: &Engine, wasi: W, limits: GuestLimits) -> wasmtime::Result<Store<WasmState<W>>> {
+        let mut store = Store::new(engine, WasmState { wasi, limits });
+        store.limiter(|state| &mut state.limits);
+        if let Some(fuel) = self.fuel {
+            store.set_fuel(fuel)?;
+        }
+
+        // Every epoch bump ends in the callback, which stops the guest once it is
+        // interrupted or past its deadline and otherwise lets the runtime run.
+        let (interrupt, deadline) = (self.interrupt.clone(), self.deadline);
+        store.set_epoch_deadline(1);
+        store.epoch_deadline_callback(move |_| {
+            if interrupt.is_requested() || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(Trap::Interrupt.into());
+            }
+            Ok(UpdateDeadline::Yield(1))
+        });
+        Ok(store)
+    }
+
+    fn finish<W>(&self, store: Store<WasmState<W>>, outcome: wasmtime::Result<()>, memory_bytes: Option<u64>, started: bool) -> GuestRun {
+        let mut usage = WorkerUsage { wasm_memory_bytes: memory_bytes, ..Default::default() };
+        // The fuel counter is not written back when an epoch deadline unwinds the
+        // guest, so what it would say then is meaningless.
+        let stopped_by_epoch = outcome.as_ref().err().and_then(|e| e.downcast_ref::<Trap>()) == Some(&Trap::Interrupt);
+        if !stopped_by_epoch {
+            usage.fuel_consumed = self.fuel.and_then(|fuel| store.get_fuel().ok().map(|left| fuel - left));
+        }
+        GuestRun { outcome, started, usage, limit_hit: store.data().limits.hit() }
+    }
+}
@@ -0,0 +1,151 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use wasmtime::{Engine, ResourceLimiter, ResourcesRequired, StoreLimits, StoreLimitsBuilder};
+
+use crate::engine::exec_engine::ZephirInvokationError;
+use crate::models::config;
+
+/// A limit a WASM guest ran into.
+#[derive(Debug, Clone)]
+pub enum LimitHit {
+    Memory(String),
+    Table(String),
+    Count(String),
+}
+
+impl From<LimitHit> for ZephirInvokationError {
+    fn from(hit: LimitHit) -> Self {
+        match hit {
+            LimitHit::Memory(message) => Self::MemoryLimit(message),
+            LimitHit::Table(message) | LimitHit::Count(message) => Self::ResourceLimit(message),
+        }
+    }
+}
+
+/// [`StoreLimits`] that remember the last growth they refused. A refused
+/// `memory.grow` returns -1 to the guest like a failed allocation would, and a
+/// guest that fails afterwards is reported as having hit the limit.
+pub struct GuestLimits {
+    limits: StoreLimits,
+    hit: Option<LimitHit>,
+}
+
+fn to_usize(value: u64) -> usize {
+    usize::try_from(value).unwrap_or(usize::MAX)
+}
+
+impl GuestLimits {
+    pub fn new(resources: &config::ResourceConfig) -> Self {
+        let wasm = &resources.wasm;
+        let mut builder = StoreLimitsBuilder::new().memory_size(to_usize(wasm.memory.unwrap_or(resources.memory)));
+        if let Some(elements) = wasm.tableElements {
+            builder = builder.table_elements(to_usize(elements));
+        }
+        if let Some(instances) = wasm.instances {
+            builder = builder.instances(to_usize(instances));
+        }
+        if let Some(tables) = wasm.tables {
+            builder = builder.tables(to_usize(tables));
+        }
+        if let Some(memories) = wasm.memories {
+            builder = builder.memories(to_usize(memories));
+        }
+        Self { limits: builder.build(), hit: None }
+    }
+
+    /// Refuses a guest that would create more tables or memories than allowed.
+    /// wasmtime refuses those itself without telling the limiter, so they are
+    /// checked up front. `None`, a component instantiating imported modules,
+    /// is left to wasmtime, like the number of instances.
+    pub fn admit(&mut self, required: Option<ResourcesRequired>) -> wasmtime::Result<()> {
+        let Some(required) = required else { return Ok(()) };
+        let (tables, memories) = (required.num_tables as usize, required.num_memories as usize);
+        let message = if memories > self.limits.memories() {
+            format!("the guest has {memories} linear memories, more than the {} allowed", self.limits.memories())
+        } else if tables > self.limits.tables() {
+            format!("the guest has {tables} tables, more than the {} allowed", self.limits.tables())
+        } else {
+            return Ok(());
+        };
+        self.hit = Some(LimitHit::Count(message.clone()));
+        Err(wasmtime::Error::msg(message))
+    }
+
+    pub fn hit(&self) -> Option<LimitHit> {
+        self.hit.clone()
+    }
+}
+
+impl ResourceLimiter for GuestLimits {
+    fn memory_growing(&mut self, current: usize, desired: usize, maximum: Option<usize>) -> wasmtime::Result<bool> {
+        let allowed = self.limits.memory_growing(current, desired, maximum)?;
+        if !allowed {
+            self.hit = Some(LimitHit::Memory(format!("linear memory could not grow from {current} to {desired} bytes")));
+        }
+        Ok(allowed)
+    }
+
+    fn table_growing(&mut self, current: usize, desired: usize, maximum: Option<usize>) -> wasmtime::Result<bool> {
+        let allowed = self.limits.table_growing(current, desired, maximum)?;
+        if !allowed {
+            self.hit = Some(LimitHit::Table(format!("table could not grow from {current} to {desired} elements")));
+        }
+        Ok(allowed)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.instances()
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.tables()
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.memories()
+    }
+}
+
+/// Fuel a guest gets per second of `cpuLimit`. Most instructions cost one unit
+/// of fuel; a tight loop burns through about this many a second, so fuel usually
+/// runs out before `RLIMIT_CPU` does.
+pub const FUEL_PER_CPU_SECOND: u64 = 500_000_000;
+
+/// How often the epoch advances while a guest runs, and so how often it yields
+/// and checks for interruptions and its deadline.
+pub const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// The fuel budget of a guest: `resources.wasm.fuel`, or `cpuLimit` converted
+/// at [`FUEL_PER_CPU_SECOND`]. `None` runs without fuel metering.
+pub fn fuel_budget(resources: &config::ResourceConfig) -> Option<u64> {
+    resources.wasm.fuel.or_else(|| (resources.cpuLimit > 0).then(|| resources.cpuLimit.saturating_mul(FUEL_PER_CPU_SECOND)))
+}
+
+/// Advances an engine's epoch every [`EPOCH_TICK`] until dropped, so that a
+/// store's deadline callback gets to run.
+pub struct EpochTicker {
+    stop: Arc<AtomicBool>,
+}
+
+impl EpochTicker {
+    pub fn start(engine: &Engine) -> std::io::Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (engine, stopped) = (engine.clone(), stop.clone());
+        std::thread::Builder::new()
+            .name("zephir-epoch".to_string())
+            .spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    std::thread::sleep(EPOCH_TICK);
+                    engine.increment_epoch();
+                }
+            })?;
+        Ok(Self { stop })
+    }
+}
+
+impl Drop for EpochTicker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,94 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use nix::sys::signal::{SigSet, Signal};
+
+use crate::engine::guest_interrupt::GuestInterrupt;
+
+use crate::engine::exec_engine::{ZephirEngine, ZephirInvokationError};
+use crate::logger::worker_logger;
+use crate::models::protocol::{WorkerError, WorkerMessage, WorkerRequest};
+
+/// The worker's end of the control socket shared with the parent Zephir process.
+#[derive(Clone)]
+pub struct WorkerChannel {
+    stream: Arc<Mutex<UnixStream>>,
+}
+
+impl WorkerChannel {
+    pub fn send(&self, message: &WorkerMessage) -> io::Result<()> {
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+
+        let mut stream = self.stream.lock().unwrap_or_else(|e| e.into_inner());
+        stream.write_all(&line)
+    }
+}
+
+fn read_request(stream: &UnixStream) -> io::Result<WorkerRequest> {
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Turn SIGTERM from the parent (timeout or shutdown) into a guest interruption,
+/// so the worker can still report back instead of dying mid-run.
+fn interrupt_on_sigterm(interrupt: Arc<GuestInterrupt>) -> io::Result<()> {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGTERM);
+    // Threads spawned from here on inherit the mask, so only the watcher sees SIGTERM.
+    signals.thread_block().map_err(io::Error::from)?;
+
+    std::thread::Builder::new()
+        .name("zephir-sigterm".to_string())
+        .spawn(move || {
+            if signals.wait().is_ok() {
+                interrupt.interrupt();
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Entry point of `zephir worker`: read the job from the control socket on `fd`,
+/// sandbox this process, run the WASM/Lua guest and report back.
+///
+/// Returns the worker's exit code: the guest's own when it exited with one.
+pub async fn run_worker(fd: RawFd) -> i32 {
+    // SAFETY: the parent hands the socket over as `fd` and nothing else in this process owns it.
+    let stream = unsafe { UnixStream::from_raw_fd(fd) };
+
+    let request = match read_request(&stream) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("zephir worker: failed to read request: {e}");
+            return 2;
+        }
+    };
+
+    let channel = WorkerChannel { stream: Arc::new(Mutex::new(stream)) };
+    let debug_enabled = request.config.logConfig.as_ref().is_some_and(|c| c.debugEnabled);
+    if let Err(e) = worker_logger::setup_logger(channel.clone(), debug_enabled) {
+        eprintln!("zephir worker: logger setup failed: {e}");
+    }
+
+    let engine = ZephirEngine::new(request.config);
+    if let Err(e) = interrupt_on_sigterm(engine.guest_interrupt()) {
+        eprintln!("zephir worker: cannot watch for SIGTERM: {e}");
+    }
+
+    let result = engine.run_guest(&request.sandbox_path, &request.args, &request.env).await;
+
+    let _ = channel.send(&WorkerMessage::Usage(engine.guest_usage()));
+    let _ = channel.send(&WorkerMessage::Result {
+        error: result.as_ref().err().map(WorkerError::from),
+    });
+
+    // A guest that exited with a code passes it on, so the worker's status matches the guest's.
+    match &result {
+        Ok(()) => 0,
+        Err(ZephirInvokationError::Exited(status)) => status.code().unwrap_or(1),
+        Err(_) => 1,
+    }
+}
@@ -1 +1,2 @@
 pub mod zephir_logger;
+pub mod worker_logger;
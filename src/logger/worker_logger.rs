@@ -0,0 +1,35 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::engine::worker_engine::WorkerChannel;
+use crate::models::protocol::WorkerMessage;
+
+/// Forwards log records from a worker process to its parent, which replays them
+/// through its own logger so prefixes and destinations stay consistent.
+struct WorkerLogger {
+    channel: WorkerChannel,
+}
+
+impl Log for WorkerLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let _ = self.channel.send(&WorkerMessage::Log {
+            level: record.level().to_string(),
+            message: record.args().to_string(),
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn setup_logger(channel: WorkerChannel, debug_enabled: bool) -> Result<(), log::SetLoggerError> {
+    log::set_boxed_logger(Box::new(WorkerLogger { channel }))?;
+    log::set_max_level(if debug_enabled {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    });
+    Ok(())
+}
@@ -1,12 +1,11 @@
 use crate::models::config;
-use chrono::Local;
-use fern::Dispatch;
 use log;
 
 pub fn setup_logger(cfg: &config::LogConfig) -> Result<(), fern::InitError> {
     let prefix = cfg.prefix.clone().unwrap_or_default();
     let debug_enabled = cfg.debugEnabled;
     let to_stdout = cfg.toStdout;
+    let to_stderr = cfg.toStderr;
     let to_file = cfg.toFile;
     let file_path = cfg.filePath.clone();
 
@@ -30,10 +29,12 @@ pub fn setup_logger(cfg: &config::LogConfig) -> Result<(), fern::InitError> {
         base_config = base_config.chain(std::io::stdout());
     }
 
-    if to_file {
-        if let Some(path) = file_path {
-            base_config = base_config.chain(fern::log_file(path)?);
-        }
+    if to_stderr {
+        base_config = base_config.chain(std::io::stderr());
+    }
+
+    if to_file && let Some(path) = file_path {
+        base_config = base_config.chain(fern::log_file(path)?);
     }
 
     base_config.apply()?;
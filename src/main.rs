@@ -4,16 +4,18 @@ mod engine;
 mod logger;
 mod compress;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use log::{info, error};
+use std::process::ExitCode;
 use std::time::Instant;
 use std::fs;
 use std::path::Path;
-use serde_yaml;
-use models::config;
-use engine::{exec_engine, pack_engine};
+use models::{config, exit_code};
+use models::result::InvocationResult;
+use engine::{doctor_engine, exec_engine, pack_engine, worker_engine};
 use utils::fs::yaml;
 use logger::zephir_logger;
+use tokio::io::AsyncReadExt;
 use tokio::signal;
 use std::sync::Arc;
 use tokio::sync::Notify;
@@ -58,6 +60,15 @@ enum Commands {
         sandbox: String,
         #[arg(short, long, default_value = "./zephir.yaml")]
         config: String,
+        /// Extra environment variable for the function, overriding the config.
+        #[arg(short, long = "env", value_name = "KEY=VAL", value_parser = parse_env_var)]
+        env: Vec<(String, String)>,
+        /// Event payload for the function: a file, or `-` for stdin.
+        #[arg(short, long, value_name = "FILE|-")]
+        input: Option<String>,
+        /// How to report the invocation result.
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
     },
 
     /// Run the full pipeline (unpack + sandbox + invoke)
@@ -67,11 +78,101 @@ enum Commands {
 
         #[arg(short, long, default_value = "./zephir.yaml")]
         config: String,
+
+        /// Extra environment variable for the function, overriding the config.
+        #[arg(short, long = "env", value_name = "KEY=VAL", value_parser = parse_env_var)]
+        env: Vec<(String, String)>,
+
+        /// Event payload for the function: a file, or `-` for stdin.
+        #[arg(short, long, value_name = "FILE|-")]
+        input: Option<String>,
+
+        /// How to report the invocation result.
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+    },
+
+    /// Report which isolation features the host offers, and check a config against them.
+    Doctor {
+        /// Config whose requirements to check; the host is still probed without one.
+        #[arg(short, long)]
+        config: Option<String>,
+
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
     },
+
+    /// Run a WASM/Lua guest on behalf of a parent Zephir process (internal).
+    #[command(hide = true)]
+    Worker {
+        #[arg(long, default_value_t = exec_engine::WORKER_FD)]
+        fd: i32,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Log the outcome only.
+    Text,
+    /// Print the invocation result as JSON on stdout.
+    Json,
+}
+
+/// Keep stdout for the JSON result by moving stdout logging to stderr.
+fn redirect_logs_for(output: OutputFormat, zephir_config: &mut config::ZephirConfig) {
+    if output == OutputFormat::Json && let Some(log_cfg) = &mut zephir_config.logConfig {
+        log_cfg.toStderr |= log_cfg.toStdout;
+        log_cfg.toStdout = false;
+    }
+}
+
+fn report(result: &InvocationResult, output: OutputFormat) {
+    if let Some(failure) = &result.failure {
+        error!("Invocation failed: {}", failure);
+    }
+    if output == OutputFormat::Json {
+        match serde_json::to_string_pretty(result) {
+            Ok(json) => println!("{json}"),
+            Err(e) => error!("Failed to serialize the invocation result: {}", e),
+        }
+    } else {
+        // Only the JSON result points at spilled output; the logs already had it.
+        result.remove_spilled_output();
+    }
+}
+
+fn parse_env_var(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, val)) if !key.is_empty() => Ok((key.to_string(), val.to_string())),
+        _ => Err(format!("expected KEY=VAL, got '{value}'")),
+    }
+}
+
+async fn read_input(input: &str) -> std::io::Result<Vec<u8>> {
+    if input == "-" {
+        let mut payload = Vec::new();
+        tokio::io::stdin().read_to_end(&mut payload).await?;
+        return Ok(payload);
+    }
+    tokio::fs::read(input).await
+}
+
+async fn build_invocation(args: Vec<String>, input: Option<&str>) -> Option<exec_engine::Invocation> {
+    let payload = match input {
+        Some(input) => match read_input(input).await {
+            Ok(payload) => Some(payload),
+            Err(e) => {
+                error!("Failed to read input '{}': {}", input, e);
+                return None;
+            }
+        },
+        None => None,
+    };
+    Some(exec_engine::Invocation { args, payload })
 }
 
 #[tokio::main(flavor = "current_thread")]
-async fn main() {
+async fn main() -> ExitCode {
     let shutdown_notify = Arc::new(Notify::new());
     let shutdown_listener = shutdown_notify.clone();
 
@@ -87,89 +188,92 @@ async fn main() {
         Commands::Init { output } => {
             if Path::new(output).exists() {
                 error!("Config file '{}' already exists!", output);
-                return;
+                return ExitCode::from(exit_code::FAILURE);
             }
             let default_config = config::ZephirConfig::sane_defaults();
             match fs::write(output, serde_yaml::to_string(&default_config).unwrap()) {
                 Ok(_) => info!("Default config written to {}", output),
-                Err(e) => error!("Failed to write default config: {}", e),
+                Err(e) => {
+                    error!("Failed to write default config: {}", e);
+                    return ExitCode::from(exit_code::IO);
+                }
             }
         }
 
         Commands::Package { dir, output } => {
-            let package_engine = pack_engine::PackageEngine::new(&dir, output.as_deref());
+            let package_engine = pack_engine::PackageEngine::new(dir, output.as_deref());
             match package_engine.package().await {
                 Ok(_) => info!("Package successful"),
-                Err(e) => error!("Package failed: {}", e),
+                Err(e) => {
+                    error!("Package failed: {}", e);
+                    return ExitCode::from(e.category().exit_code());
+                }
             }
         }
 
         Commands::Unpack { no_cache, config: cfg_path } => {
-            let zephir_config = match yaml::parse_yaml_from_file::<config::ZephirConfig>(&cfg_path).await {
+            let zephir_config = match yaml::parse_yaml_from_file::<config::ZephirConfig>(cfg_path).await {
                 Ok(c) => c,
                 Err(e) => {
                     error!("Failed to parse config: {}", e);
-                    return;
+                    return ExitCode::from(e.category().exit_code());
                 }
             };
             let engine = exec_engine::ZephirEngine::new(zephir_config);
 
             match engine.unpack(*no_cache).await {
                 Ok(path) => info!("Artifact unpacked to {}", path),
-                Err(e) => error!("Unpack failed: {}", e),
+                Err(e) => {
+                    error!("Unpack failed: {}", e);
+                    return ExitCode::from(exit_code::UNPACK);
+                }
             }
         }
 
-        Commands::Invoke { args, sandbox, config: cfg_path } => {
-            let zephir_config = match yaml::parse_yaml_from_file::<config::ZephirConfig>(&cfg_path).await {
+        Commands::Invoke { args, sandbox, config: cfg_path, env, input, output } => {
+            let mut zephir_config = match yaml::parse_yaml_from_file::<config::ZephirConfig>(cfg_path).await {
                 Ok(c) => c,
                 Err(e) => {
                     error!("Failed to parse config: {}", e);
-                    return;
+                    return ExitCode::from(e.category().exit_code());
                 }
             };
+            zephir_config.function.env.extend(env.iter().cloned());
+            redirect_logs_for(*output, &mut zephir_config);
             let engine = exec_engine::ZephirEngine::new(zephir_config);
 
             // Run the actual work as a future
             let engine_clone = Arc::new(engine);
             let sandbox_clone = sandbox.clone();
-            let args_clone: Vec<String> = args.clone();
-
-            tokio::select! {
-                _ = async {
-                    // Synchronous calls wrapped in async
-                    if let Err(e) = engine_clone.sandbox(&sandbox_clone) {
-                        error!("Failed to setup sandbox: {}", e);
-                        return;
-                    }
-
-                    if let Err(e) = engine_clone.invoke(
-                        &args_clone.iter().map(|s| s.as_str()).collect::<Vec<&str>>(),
-                        &sandbox_clone
-                    ).await {
-                        error!("Invocation failed: {}", e);
-                    }
+            let Some(invocation) = build_invocation(args.clone(), input.as_deref()).await else {
+                return ExitCode::from(exit_code::IO);
+            };
 
-                    if let Err(e) = engine_clone.cleanup_sandbox(&sandbox_clone) {
-                        error!("Cleanup failed: {}", e);
-                    }
-                } => {},
+            let run = engine_clone.run_in_sandbox(&sandbox_clone, &invocation);
+            tokio::pin!(run);
+            let result = tokio::select! {
+                result = &mut run => result,
 
                 _ = shutdown_notify.notified() => {
-                    info!("Graceful shutdown requested. Cleaning up...");
-                    let _ = engine_clone.cleanup_sandbox(&sandbox_clone);
+                    info!("Graceful shutdown requested. Stopping the function and cleaning up...");
+                    engine_clone.cancel();
+                    run.await
                 }
-            }
+            };
+            report(&result, *output);
+            return ExitCode::from(result.cli_exit_code());
         }
 
-        Commands::Run { no_cache, config: cfg_path } => {
-            let zephir_config = match yaml::parse_yaml_from_file::<config::ZephirConfig>(&cfg_path).await {
+        Commands::Run { no_cache, config: cfg_path, env, input, output } => {
+            let mut zephir_config = match yaml::parse_yaml_from_file::<config::ZephirConfig>(cfg_path).await {
                 Ok(c) => c,
                 Err(e) => {
                     error!("Failed to parse config: {}", e);
-                    return;
+                    return ExitCode::from(e.category().exit_code());
                 }
             };
+            zephir_config.function.env.extend(env.iter().cloned());
+            redirect_logs_for(*output, &mut zephir_config);
             let engine = Arc::new(exec_engine::ZephirEngine::new(zephir_config));
 
             if let Some(log_cfg) = &engine.config.logConfig {
@@ -182,40 +286,63 @@ async fn main() {
             let start = Instant::now();
             info!("Starting full execution pipeline...");
 
+            let Some(invocation) = build_invocation(Vec::new(), input.as_deref()).await else {
+                return ExitCode::from(exit_code::IO);
+            };
             let engine_clone = engine.clone();
 
-            tokio::select! {
-                _ = async {
-                    let sandbox_path = match engine_clone.unpack(*no_cache).await {
-                        Ok(path) => path,
-                        Err(e) => {
-                            error!("Failed to unpack sandbox: {}", e);
-                            return;
-                        }
-                    };
-
-                    if let Err(e) = engine_clone.sandbox(&sandbox_path) {
-                        error!("Sandbox setup failed: {}", e);
-                        return;
-                    }
+            let run = engine_clone.run(*no_cache, &invocation);
+            tokio::pin!(run);
+            let result = tokio::select! {
+                result = &mut run => result,
 
-                    if let Err(e) = engine_clone.invoke(&[], &sandbox_path).await {
-                        error!("Invocation failed: {}", e);
-                    }
+                _ = shutdown_notify.notified() => {
+                    info!("Graceful shutdown requested during Run. Stopping the function and cleaning up...");
+                    engine_clone.cancel();
+                    run.await
+                }
+            };
+            report(&result, *output);
+            let duration = start.elapsed();
+            info!("Full pipeline completed in {:.2?}", duration);
+            return ExitCode::from(result.cli_exit_code());
+        }
 
-                    if let Err(e) = engine_clone.cleanup_sandbox(&sandbox_path) {
-                        error!("Cleanup failed: {}", e);
+        Commands::Doctor { config: cfg_path, output } => {
+            let zephir_config = match cfg_path {
+                Some(cfg_path) => match yaml::parse_yaml_from_file::<config::ZephirConfig>(cfg_path).await {
+                    Ok(c) => c,
+                    Err(e) => {
+                        eprintln!("Failed to parse config: {}", e);
+                        return ExitCode::from(e.category().exit_code());
                     }
+                },
+                None => config::ZephirConfig::sane_defaults(),
+            };
+            let engine = exec_engine::ZephirEngine::new(zephir_config);
 
-                    let duration = start.elapsed();
-                    info!("Full pipeline completed in {:.2?}", duration);
-                } => {},
-
-                _ = shutdown_notify.notified() => {
-                    info!("Graceful shutdown requested during Run.");
-                    // Note: You could track sandbox path separately if needed
-                }
+            let host = doctor_engine::probe_host(&engine.config);
+            let preflight = cfg_path.is_some().then(|| doctor_engine::preflight(&engine));
+            let report = doctor_engine::DoctorReport::new(host, preflight);
+            match output {
+                OutputFormat::Text => print!("{report}"),
+                OutputFormat::Json => match serde_json::to_string_pretty(&report) {
+                    Ok(json) => println!("{json}"),
+                    Err(e) => {
+                        eprintln!("Failed to serialize the report: {}", e);
+                        return ExitCode::from(exit_code::FAILURE);
+                    }
+                },
+            }
+            if report.has_problems() {
+                return ExitCode::from(exit_code::CONFIG);
             }
         }
+
+        Commands::Worker { fd } => {
+            std::process::exit(worker_engine::run_worker(*fd).await);
+        }
     }
+
+    ExitCode::SUCCESS
 }
@@ -1,12 +1,17 @@
+#![allow(non_snake_case)]
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize, Default)]
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct ApplicationConfig {
     #[serde(default)]
     pub entry: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, Default)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub enum ArtifactType {
     #[default]
     NATIVE,
@@ -14,7 +19,7 @@ pub enum ArtifactType {
     LUA,
 } 
 
-#[derive(Debug, Deserialize, Serialize, Default)]
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct ArtifactConfig {
     pub packagePath: String,
 
@@ -34,33 +39,323 @@ fn default_cpu_time() -> u64 {
     10_u64
 }
 
-#[derive(Debug, Deserialize, Serialize, Default)]
+fn default_grace_period() -> u64 {
+    5_u64
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// A single `io.max` entry, keyed by block device number (`major:minor`).
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+pub struct IoLimit {
+    pub device: String,
+    pub rbps: Option<u64>,
+    pub wbps: Option<u64>,
+    pub riops: Option<u64>,
+    pub wiops: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CgroupConfig {
+    #[serde(default="default_true")]
+    pub enabled: bool,
+
+    /// Parent cgroup for per-invocation cgroups. Defaults to `/sys/fs/cgroup/zephir`
+    /// as root and to the delegated `user@<uid>.service` subtree otherwise.
+    pub parent: Option<String>,
+
+    /// CPU bandwidth in cores (e.g. `0.5`), written to `cpu.max`.
+    pub cpus: Option<f64>,
+
+    pub pidsMax: Option<u64>,
+
+    #[serde(default)]
+    pub io: Vec<IoLimit>,
+}
+
+impl Default for CgroupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            parent: None,
+            cpus: None,
+            pidsMax: None,
+            io: Vec::new(),
+        }
+    }
+}
+
+/// Limits for WASM guests, enforced by the wasmtime store.
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+pub struct WasmLimitsConfig {
+    /// Bytes per linear memory; defaults to `resources.memory`.
+    pub memory: Option<u64>,
+
+    /// Elements per table.
+    pub tableElements: Option<u64>,
+
+    /// Instances, tables and linear memories in the store; wasmtime allows 10000 of each by default.
+    pub instances: Option<u64>,
+    pub tables: Option<u64>,
+    pub memories: Option<u64>,
+
+    /// Fuel budget; defaults to `cpuLimit` seconds' worth.
+    pub fuel: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct ResourceConfig {
     
     #[serde(default="default_memory")]
     pub memory: u64,
 
+    /// Total bytes the sandbox directory may hold.
     #[serde(default="default_storage")]
     pub storage: u64,
 
+    /// Files and directories the sandbox directory may hold; unset means no limit.
+    pub inodes: Option<u64>,
+
     #[serde(default="default_cpu_time")]
-    pub cpuLimit: u64
+    pub cpuLimit: u64,
+
+    /// Wall-clock limit in seconds; unset means no limit. Lua scripts with a
+    /// timeout run with the LuaJIT compiler off so that they can be stopped,
+    /// which makes hot loops several times slower.
+    pub timeout: Option<u64>,
+
+    /// Seconds between SIGTERM and SIGKILL once the timeout hits.
+    #[serde(default="default_grace_period")]
+    pub gracePeriod: u64,
+
+    #[serde(default)]
+    pub cgroup: CgroupConfig,
+
+    /// Processes the function may run at once. Linux counts threads as processes,
+    /// so the lower of this and `maxThreads` caps all of its tasks (NATIVE only).
+    pub maxProcesses: Option<u64>,
+
+    pub maxThreads: Option<u64>,
+
+    /// Open file descriptors per process.
+    pub maxOpenFiles: Option<u64>,
+
+    /// Stack size in bytes.
+    pub maxStack: Option<u64>,
+
+    #[serde(default)]
+    pub wasm: WasmLimitsConfig,
+}
+
+
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FilesystemConfig {
+    /// Confine the function with Landlock where the kernel supports it.
+    #[serde(default="default_true")]
+    pub landlock: bool,
+
+    /// Host paths the function may read and execute from (e.g. `/lib`, `/usr/lib`).
+    #[serde(default)]
+    pub readPaths: Vec<String>,
+}
+
+impl Default for FilesystemConfig {
+    fn default() -> Self {
+        Self {
+            landlock: true,
+            readPaths: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkMode {
+    /// No network at all, not even loopback.
+    None,
+    /// A private network namespace with only `lo` up.
+    Loopback,
+    /// The host network, optionally narrowed by `allow`.
+    #[default]
+    Host,
 }
 
+/// Destination a function may connect to in `host` mode.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct NetworkRule {
+    /// `10.0.0.0/8`, `2001:db8::/32` or a bare address.
+    pub cidr: String,
 
+    /// Allowed destination ports; empty means any port.
+    #[serde(default)]
+    pub ports: Vec<u16>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+pub struct NetworkConfig {
+    #[serde(default)]
+    pub mode: NetworkMode,
+
+    /// Enforced for WASM guests only; NATIVE functions with an allowlist are refused.
+    #[serde(default)]
+    pub allow: Vec<NetworkRule>,
+}
 
-#[derive(Debug, Deserialize, Serialize, Default)]
+#[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MountType {
+    /// A host file or directory.
+    #[default]
+    Bind,
+    /// An empty in-memory filesystem.
+    Tmpfs,
+}
+
+/// A host path or tmpfs made available inside the sandbox.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MountConfig {
+    #[serde(default, rename = "type")]
+    pub mountType: MountType,
+
+    /// Host path to bind; required for `bind`.
+    pub source: Option<String>,
+
+    /// Path inside the sandbox.
+    pub target: String,
+
+    #[serde(default)]
+    pub readOnly: bool,
+
+    /// tmpfs size limit in bytes.
+    pub size: Option<u64>,
+}
+
+fn default_id_count() -> u32 {
+    1
+}
+
+/// `count` ids starting at `inside` the user namespace map to ids starting at `outside` on the host.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IdMapEntry {
+    pub inside: u32,
+    pub outside: u32,
+    #[serde(default="default_id_count")]
+    pub count: u32,
+}
+
+/// User namespace for NATIVE functions; empty maps map the target ids to Zephir's own.
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+pub struct UserNamespaceConfig {
+    #[serde(default)]
+    pub uidMap: Vec<IdMapEntry>,
+
+    #[serde(default)]
+    pub gidMap: Vec<IdMapEntry>,
+}
+
+/// Identity the function runs as. Without any of it, root drops to nobody/nogroup.
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+pub struct RunAsConfig {
+    /// Named user; its uid, primary gid and groups are the defaults for the fields below.
+    pub user: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Supplementary groups.
+    pub groups: Option<Vec<u32>>,
+    pub userNamespace: Option<UserNamespaceConfig>,
+}
+
+fn default_max_line_length() -> usize {
+    16 * 1024
+}
+
+fn default_max_output() -> u64 {
+    16 * 1024 * 1024
+}
+
+/// How much of the function's stdout and stderr is kept.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OutputConfig {
+    /// Bytes of stdout captured and logged; the rest is dropped after a truncation marker.
+    #[serde(default="default_max_output")]
+    pub maxStdout: u64,
+    #[serde(default="default_max_output")]
+    pub maxStderr: u64,
+
+    /// Longer lines are logged in pieces of this many bytes.
+    #[serde(default="default_max_line_length")]
+    pub maxLineLength: usize,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            maxStdout: default_max_output(),
+            maxStderr: default_max_output(),
+            maxLineLength: default_max_line_length(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct FunctionConfig {
     #[serde(default)]
     pub bundle: ArtifactConfig,
     pub app: ApplicationConfig,
 
+    /// Variables passed to the function; they override `envFrom`.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    /// Dotenv file to load variables from.
+    pub envFrom: Option<String>,
+
+    /// Start from an empty environment instead of Zephir's own.
+    #[serde(default="default_true")]
+    pub clearEnv: bool,
+
     #[serde(default)]
     pub resources: ResourceConfig,
+
+    #[serde(default)]
+    pub filesystem: FilesystemConfig,
+
+    #[serde(default)]
+    pub network: NetworkConfig,
+
+    #[serde(default)]
+    pub runAs: RunAsConfig,
+
+    #[serde(default)]
+    pub mounts: Vec<MountConfig>,
+
+    #[serde(default)]
+    pub output: OutputConfig,
+}
+
+impl Default for FunctionConfig {
+    fn default() -> Self {
+        Self {
+            bundle: ArtifactConfig::default(),
+            app: ApplicationConfig::default(),
+            env: BTreeMap::new(),
+            envFrom: None,
+            clearEnv: true,
+            resources: ResourceConfig::default(),
+            filesystem: FilesystemConfig::default(),
+            network: NetworkConfig::default(),
+            runAs: RunAsConfig::default(),
+            mounts: Vec::new(),
+            output: OutputConfig::default(),
+        }
+    }
 }
 
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct StorageConfig {
     pub sandbox: Option<String>,
     pub cache: Option<String>,
@@ -75,7 +370,7 @@ impl StorageConfig {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct LogConfig {
     #[serde(default)]
     pub toFile: bool,
@@ -83,6 +378,10 @@ pub struct LogConfig {
 
     #[serde(default)]
     pub toStdout: bool,
+
+    /// Log to stderr, e.g. to keep stdout free for `--output json`.
+    #[serde(default)]
+    pub toStderr: bool,
     pub prefix: Option<String>,
 
     #[serde(default)]
@@ -93,7 +392,7 @@ fn default_name() -> String {
     String::from("zephir-function")
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ZephirConfig {
     #[serde(default="default_name")]
     pub name: String,
@@ -106,6 +405,14 @@ pub struct ZephirConfig {
 }
 
 impl ZephirConfig {
+    /// The cache and sandbox directories, with the defaults filled in.
+    pub fn storage_dirs(&self) -> (PathBuf, PathBuf) {
+        let defaults = StorageConfig::sane_defaults();
+        let storage = self.storage.as_ref().unwrap_or(&defaults);
+        let cache = storage.cache.as_ref().or(defaults.cache.as_ref()).map(PathBuf::from).unwrap_or_default();
+        let sandbox = storage.sandbox.as_ref().or(defaults.sandbox.as_ref()).map(PathBuf::from).unwrap_or_default();
+        (cache, sandbox)
+    }
 
     pub fn sane_defaults() -> Self {
         ZephirConfig {
@@ -118,17 +425,35 @@ impl ZephirConfig {
                     packagePath: "function.zephir".to_string(),
                     artifactType: ArtifactType::NATIVE,
                 },
+                env: BTreeMap::new(),
+                envFrom: None,
+                clearEnv: true,
                 resources: ResourceConfig {
                     memory: default_memory(),
                     storage: default_storage(),
+                    inodes: None,
                     cpuLimit: default_cpu_time(),
+                    timeout: None,
+                    gracePeriod: default_grace_period(),
+                    cgroup: CgroupConfig::default(),
+                    maxProcesses: None,
+                    maxThreads: None,
+                    maxOpenFiles: None,
+                    maxStack: None,
+                    wasm: WasmLimitsConfig::default(),
                 },
+                filesystem: FilesystemConfig::default(),
+                network: NetworkConfig::default(),
+                runAs: RunAsConfig::default(),
+                mounts: Vec::new(),
+                output: OutputConfig::default(),
             },
             storage: Some(StorageConfig::sane_defaults()),
             logConfig: Some(LogConfig {
                 toFile: false,
                 filePath: None,
                 toStdout: true,
+                toStderr: false,
                 prefix: Some("[Zephir]".to_string()),
                 debugEnabled: false,
             }),
@@ -0,0 +1,25 @@
+// Exit codes of the `zephir-rs` CLI. Codes for Zephir's own failures follow
+// sysexits(3) and timeout(1); a NATIVE function's own non-zero exit code is
+// passed through, and a function killed by signal N exits with 128 + N.
+
+pub const SUCCESS: u8 = 0;
+/// Generic failure, and the fallback for a function that failed without a code.
+pub const FAILURE: u8 = 1;
+/// The artifact could not be unpacked or failed its integrity checks (EX_DATAERR).
+pub const UNPACK: u8 = 65;
+/// WASM trap or Lua runtime error (EX_SOFTWARE).
+pub const TRAP: u8 = 70;
+/// The sandbox could not be set up (EX_OSERR).
+pub const SANDBOX: u8 = 71;
+/// I/O error outside the function (EX_IOERR).
+pub const IO: u8 = 74;
+/// The configuration could not be read or is invalid (EX_CONFIG).
+pub const CONFIG: u8 = 78;
+/// The wall-clock timeout was reached.
+pub const TIMEOUT: u8 = 124;
+/// The function was killed for exceeding its memory, CPU time or file size limit.
+pub const RESOURCE_LIMIT: u8 = 125;
+/// Base for functions killed by a signal.
+pub const SIGNALED: u8 = 128;
+/// Zephir itself was interrupted (128 + SIGINT).
+pub const INTERRUPTED: u8 = 130;
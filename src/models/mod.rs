@@ -1 +1,4 @@
 pub mod config;
+pub mod exit_code;
+pub mod protocol;
+pub mod result;
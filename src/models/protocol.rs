@@ -0,0 +1,69 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+
+use crate::models::config::ZephirConfig;
+
+// Messages exchanged between Zephir and a `zephir worker` process, one JSON
+// document per line over the socket the worker inherits as fd 3.
+
+/// The job a worker runs: sent once by the parent right after spawning it.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WorkerRequest {
+    pub config: ZephirConfig,
+    pub sandbox_path: String,
+    /// Arguments from `invoke --args`.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// The function's environment, resolved by the parent.
+    pub env: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum WorkerErrorKind {
+    Io,
+    Wasm,
+    /// The WASM guest could not be compiled, linked or instantiated.
+    WasmLoad,
+    Lua,
+    Config,
+    Sandbox,
+    MemoryLimit,
+    ResourceLimit,
+    Interrupted,
+    /// The guest ran past its wall-clock deadline.
+    Timeout,
+    /// The guest exited with a non-zero code, e.g. through WASI `proc_exit`.
+    Exit,
+    Other,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WorkerError {
+    pub kind: WorkerErrorKind,
+    pub message: String,
+    /// The guest's exit code, for `Exit`.
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    /// The deadline that passed, for `Timeout`.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+/// Counters from the guest runtime; process-level usage is measured by the parent.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct WorkerUsage {
+    pub fuel_consumed: Option<u64>,
+    pub wasm_memory_bytes: Option<u64>,
+    pub lua_instructions: Option<u64>,
+    pub lua_peak_heap_bytes: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum WorkerMessage {
+    /// A log record emitted inside the worker, replayed through Zephir's logger.
+    Log { level: String, message: String },
+    Usage(WorkerUsage),
+    /// Final message of a run; `error` is `None` when the guest succeeded.
+    Result { error: Option<WorkerError> },
+}
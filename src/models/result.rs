@@ -0,0 +1,318 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+use log::warn;
+use serde::{Serialize, Serializer};
+
+use crate::models::exit_code;
+
+/// What made an invocation fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FailureKind {
+    /// The configuration could not be read or is invalid.
+    Config,
+    /// The artifact could not be unpacked.
+    Unpack,
+    /// The sandbox could not be prepared or entered.
+    Sandbox,
+    /// The function exited with a non-zero code.
+    Exit,
+    /// The function was killed by a signal.
+    Signal,
+    Timeout,
+    MemoryLimit,
+    /// Killed for exceeding the CPU time or file size limit.
+    ResourceLimit,
+    /// A WASM trap or Lua runtime error.
+    Trap,
+    Interrupted,
+    Io,
+    Other,
+}
+
+impl FailureKind {
+    /// Exit code of the CLI for this kind of failure. `Exit` and `Signal` are
+    /// only fallbacks; see [`InvocationResult::cli_exit_code`].
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Self::Config => exit_code::CONFIG,
+            Self::Unpack => exit_code::UNPACK,
+            Self::Sandbox => exit_code::SANDBOX,
+            Self::Exit | Self::Other => exit_code::FAILURE,
+            Self::Signal => exit_code::SIGNALED,
+            Self::Timeout => exit_code::TIMEOUT,
+            Self::MemoryLimit | Self::ResourceLimit => exit_code::RESOURCE_LIMIT,
+            Self::Trap => exit_code::TRAP,
+            Self::Interrupted => exit_code::INTERRUPTED,
+            Self::Io => exit_code::IO,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub message: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Everything a function wrote to one of its output streams.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CapturedOutput {
+    /// Total bytes written.
+    pub size: u64,
+    /// The output itself, unless it was too large and spilled to `path`.
+    #[serde(serialize_with = "serialize_lossy")]
+    pub data: Option<Vec<u8>>,
+    pub path: Option<PathBuf>,
+    /// The stream went over its cap; what was kept ends with a truncation marker.
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// A run of output from one stream; the segments of a stream, in order, add up to what was kept of it.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputSegment {
+    pub stream: OutputStream,
+    /// When the first byte of the segment arrived, since the function started.
+    #[serde(rename = "atMs", serialize_with = "serialize_millis_required")]
+    pub at: Duration,
+    pub size: u64,
+}
+
+/// Order in which stdout and stderr arrived.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputTimeline {
+    pub segments: Vec<OutputSegment>,
+    /// `false` once there were too many segments to keep recording.
+    pub complete: bool,
+}
+
+impl Default for OutputTimeline {
+    fn default() -> Self {
+        Self { segments: Vec::new(), complete: true }
+    }
+}
+
+/// Wall-clock time spent in each phase; `None` for phases that did not run.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PhaseDurations {
+    #[serde(rename = "unpackMs", serialize_with = "serialize_millis")]
+    pub unpack: Option<Duration>,
+    #[serde(rename = "sandboxMs", serialize_with = "serialize_millis")]
+    pub sandbox: Option<Duration>,
+    #[serde(rename = "invokeMs", serialize_with = "serialize_millis")]
+    pub invoke: Option<Duration>,
+    #[serde(rename = "cleanupMs", serialize_with = "serialize_millis")]
+    pub cleanup: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceUsage {
+    pub user_time_us: Option<u64>,
+    pub system_time_us: Option<u64>,
+    pub max_rss_kib: Option<u64>,
+    pub minor_faults: Option<u64>,
+    pub major_faults: Option<u64>,
+    /// Filesystem reads and writes, in blocks.
+    pub block_input: Option<u64>,
+    pub block_output: Option<u64>,
+    pub voluntary_context_switches: Option<u64>,
+    pub involuntary_context_switches: Option<u64>,
+    /// Peak memory of the invocation's cgroup.
+    pub memory_peak_bytes: Option<u64>,
+    pub oom_kills: Option<u64>,
+    /// Space and inodes used in the sandbox when the function finished.
+    pub storage_bytes: Option<u64>,
+    pub storage_inodes: Option<u64>,
+    /// WASM fuel burnt, when fuel metering is on.
+    pub fuel_consumed: Option<u64>,
+    /// Final (and so peak) size of the WASM module's linear memories.
+    pub wasm_memory_bytes: Option<u64>,
+    /// Lua VM instructions executed and peak heap, sampled every few thousand instructions.
+    pub lua_instructions: Option<u64>,
+    pub lua_peak_heap_bytes: Option<u64>,
+}
+
+/// One-line summary of whatever was measured.
+impl fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = [
+            ("cpu user us", self.user_time_us),
+            ("cpu system us", self.system_time_us),
+            ("max RSS KiB", self.max_rss_kib),
+            ("peak memory bytes", self.memory_peak_bytes),
+            ("minor faults", self.minor_faults),
+            ("major faults", self.major_faults),
+            ("blocks in", self.block_input),
+            ("blocks out", self.block_output),
+            ("voluntary switches", self.voluntary_context_switches),
+            ("involuntary switches", self.involuntary_context_switches),
+            ("storage bytes", self.storage_bytes),
+            ("storage inodes", self.storage_inodes),
+            ("fuel", self.fuel_consumed),
+            ("wasm memory bytes", self.wasm_memory_bytes),
+            ("lua instructions", self.lua_instructions),
+            ("lua peak heap bytes", self.lua_peak_heap_bytes),
+        ];
+
+        let mut first = true;
+        for (label, value) in fields {
+            let Some(value) = value else { continue };
+            if !first {
+                f.write_str(", ")?;
+            }
+            write!(f, "{label} {value}")?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of a single invocation.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InvocationResult {
+    pub name: String,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub stdout: CapturedOutput,
+    pub stderr: CapturedOutput,
+    pub timeline: OutputTimeline,
+    pub durations: PhaseDurations,
+    pub usage: ResourceUsage,
+    /// Process, thread and file limits the function ran into, by config name.
+    pub limits_hit: Vec<String>,
+    /// `None` when the invocation succeeded.
+    pub failure: Option<Failure>,
+}
+
+impl InvocationResult {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_string(), ..Default::default() }
+    }
+
+    pub fn failed(name: &str, kind: FailureKind, message: String) -> Self {
+        Self { failure: Some(Failure { kind, message }), ..Self::new(name) }
+    }
+
+    /// Exit code for the CLI: the function's own when it exited non-zero,
+    /// 128 + N when it was killed by signal N, the failure's category otherwise.
+    pub fn cli_exit_code(&self) -> u8 {
+        let Some(failure) = &self.failure else {
+            return exit_code::SUCCESS;
+        };
+
+        match (failure.kind, self.exit_code, self.signal) {
+            (FailureKind::Exit, Some(code), _) if code != 0 => code as u8,
+            (FailureKind::Signal, _, Some(signal)) => exit_code::SIGNALED.saturating_add(signal as u8),
+            (kind, _, _) => kind.exit_code(),
+        }
+    }
+
+    /// Delete the files large output was spilled to, for when nobody is told where they are.
+    pub fn remove_spilled_output(&self) {
+        for path in [&self.stdout.path, &self.stderr.path].into_iter().flatten() {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Failed to remove spilled output {}: {}", path.display(), e);
+            }
+            // The invocation's spill directory goes too once it is empty.
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::remove_dir(dir);
+            }
+        }
+    }
+}
+
+fn serialize_lossy<S: Serializer>(data: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match data {
+        Some(bytes) => serializer.serialize_some(&String::from_utf8_lossy(bytes)),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_millis<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    match duration {
+        Some(d) => serializer.serialize_some(&(d.as_secs_f64() * 1000.0)),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_millis_required<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(kind: Option<FailureKind>, exit_code: Option<i32>, signal: Option<i32>) -> InvocationResult {
+        let failure = kind.map(|kind| Failure { kind, message: String::new() });
+        InvocationResult { exit_code, signal, failure, ..InvocationResult::new("test") }
+    }
+
+    #[test]
+    fn failure_kinds_map_to_sysexits_codes() {
+        let table = [
+            (FailureKind::Config, 78),
+            (FailureKind::Unpack, 65),
+            (FailureKind::Sandbox, 71),
+            (FailureKind::Exit, 1),
+            (FailureKind::Signal, 128),
+            (FailureKind::Timeout, 124),
+            (FailureKind::MemoryLimit, 125),
+            (FailureKind::ResourceLimit, 125),
+            (FailureKind::Trap, 70),
+            (FailureKind::Interrupted, 130),
+            (FailureKind::Io, 74),
+            (FailureKind::Other, 1),
+        ];
+        for (kind, code) in table {
+            assert_eq!(kind.exit_code(), code, "{kind:?}");
+            assert_eq!(result(Some(kind), None, None).cli_exit_code(), code, "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn a_successful_run_exits_zero() {
+        assert_eq!(result(None, Some(0), None).cli_exit_code(), 0);
+    }
+
+    #[test]
+    fn a_function_exit_code_is_passed_through() {
+        for code in [1, 2, 42, 255] {
+            assert_eq!(result(Some(FailureKind::Exit), Some(code), None).cli_exit_code(), code as u8);
+        }
+        // Without a code of its own, a failed exit is a generic failure.
+        assert_eq!(result(Some(FailureKind::Exit), Some(0), None).cli_exit_code(), 1);
+    }
+
+    #[test]
+    fn a_killed_function_exits_with_128_plus_the_signal() {
+        assert_eq!(result(Some(FailureKind::Signal), None, Some(9)).cli_exit_code(), 137);
+        assert_eq!(result(Some(FailureKind::Signal), None, Some(15)).cli_exit_code(), 143);
+    }
+
+    #[test]
+    fn zephir_failures_win_over_the_function_exit_code() {
+        assert_eq!(result(Some(FailureKind::Timeout), Some(3), Some(15)).cli_exit_code(), 124);
+        assert_eq!(result(Some(FailureKind::MemoryLimit), None, Some(9)).cli_exit_code(), 125);
+    }
+}
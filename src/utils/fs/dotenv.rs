@@ -0,0 +1,122 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Parse a dotenv file: `KEY=VALUE` lines, with optional `export ` prefixes,
+/// `#` comments and single- or double-quoted values.
+pub fn parse_dotenv(content: &str) -> io::Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {reason}", index + 1));
+
+        let (key, value) = line.split_once('=').ok_or_else(|| invalid("expected KEY=VALUE"))?;
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(invalid("invalid variable name"));
+        }
+
+        vars.push((key.to_string(), parse_value(value.trim()).ok_or_else(|| invalid("unterminated quote"))?));
+    }
+
+    Ok(vars)
+}
+
+fn parse_value(value: &str) -> Option<String> {
+    if let Some(rest) = value.strip_prefix('\'') {
+        // Single quotes are literal.
+        return rest.split_once('\'').map(|(v, _)| v.to_string());
+    }
+
+    if let Some(rest) = value.strip_prefix('"') {
+        let mut parsed = String::new();
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return Some(parsed),
+                '\\' => match chars.next()? {
+                    'n' => parsed.push('\n'),
+                    't' => parsed.push('\t'),
+                    other => parsed.push(other),
+                },
+                c => parsed.push(c),
+            }
+        }
+        return None;
+    }
+
+    // Unquoted: strip a trailing ` # comment`.
+    let value = match value.find(" #") {
+        Some(pos) => &value[..pos],
+        None => value,
+    };
+    Some(value.trim_end().to_string())
+}
+
+pub fn read_dotenv(path: &Path) -> io::Result<Vec<(String, String)>> {
+    let content = fs::read_to_string(path)?;
+    parse_dotenv(&content).map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn parses_plain_assignments() {
+        let parsed = parse_dotenv("A=1\n  B = two words  \nC=\nD=x=y\n").unwrap();
+        assert_eq!(parsed, vars(&[("A", "1"), ("B", "two words"), ("C", ""), ("D", "x=y")]));
+    }
+
+    #[test]
+    fn skips_blank_lines_and_comments() {
+        let parsed = parse_dotenv("\n# comment\n   # indented comment\nA=1 # trailing\nB=a#b\n").unwrap();
+        assert_eq!(parsed, vars(&[("A", "1"), ("B", "a#b")]));
+    }
+
+    #[test]
+    fn strips_export_prefixes() {
+        assert_eq!(parse_dotenv("export A=1\n").unwrap(), vars(&[("A", "1")]));
+    }
+
+    #[test]
+    fn single_quotes_are_literal() {
+        let parsed = parse_dotenv(r"A='x # \n $B' ignored").unwrap();
+        assert_eq!(parsed, vars(&[("A", r"x # \n $B")]));
+    }
+
+    #[test]
+    fn double_quotes_handle_escapes() {
+        let parsed = parse_dotenv(r#"A="line\nnext\ttab \"quoted\" \\ # kept""#).unwrap();
+        assert_eq!(parsed, vars(&[("A", "line\nnext\ttab \"quoted\" \\ # kept")]));
+    }
+
+    #[test]
+    fn later_assignments_are_kept_in_order() {
+        assert_eq!(parse_dotenv("A=1\nA=2\n").unwrap(), vars(&[("A", "1"), ("A", "2")]));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for content in ["A", "=1", "A B=1", "A-B=1", "A='open", "A=\"open", "A=\"escaped end\\\""] {
+            assert!(parse_dotenv(content).is_err(), "{content:?} parsed");
+        }
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = parse_dotenv("A=1\n\nnot an assignment\n").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("line 3:"), "{error}");
+    }
+}
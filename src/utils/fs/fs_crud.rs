@@ -26,3 +26,10 @@ pub fn copy_dir_recursive(src: &Path, dst: &Path) -> std::io::Result<()> {
     Ok(())
 }
 
+
+pub fn chown_recursive(path: &Path, uid: u32, gid: u32) -> std::io::Result<()> {
+    for entry in walkdir::WalkDir::new(path) {
+        std::os::unix::fs::lchown(entry?.path(), Some(uid), Some(gid))?;
+    }
+    Ok(())
+}
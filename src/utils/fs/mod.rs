@@ -1,3 +1,4 @@
 pub mod yaml;
 pub mod fs_crud;
 pub mod path;
+pub mod dotenv;
@@ -5,6 +5,16 @@ pub fn get_artifact_cache(cache_path: &Path) -> PathBuf {
     cache_path.join("artifact-cache")
 } 
 
+pub fn get_wasm_cache(cache_path: &Path) -> PathBuf {
+    cache_path.join("wasm-cache")
+}
+
+/// Where the invocation running in `sandbox_path` spills large output. It outlives
+/// the sandbox, so the files can still be read once the function is done.
+pub fn get_output_spill(cache_path: &Path, sandbox_path: &Path) -> PathBuf {
+    cache_path.join("output").join(sandbox_path.file_name().unwrap_or_default())
+}
+
 pub fn get_atomic_sandbox_path(sandbox_path: &Path) -> PathBuf {
     let timestamp = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -6,6 +6,8 @@ use tokio::io::AsyncWriteExt;
 use serde::{de::DeserializeOwned, Serialize};
 use serde_yaml;
 
+use crate::models::result::FailureKind;
+
 #[derive(Debug, Error)]
 pub enum ParseError {
     #[error("I/O error: {0}")]
@@ -15,6 +17,13 @@ pub enum ParseError {
     Yaml(#[from] serde_yaml::Error),
 }
 
+impl ParseError {
+    /// Parse errors are configuration errors, whether the file is missing or malformed.
+    pub fn category(&self) -> FailureKind {
+        FailureKind::Config
+    }
+}
+
 pub async fn parse_yaml_from_file<T: DeserializeOwned>(path: &str) -> Result<T, ParseError> {
     let content = fs::read_to_string(path).await?;
     let parsed = serde_yaml::from_str(&content)?;
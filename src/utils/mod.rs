@@ -1,3 +1,3 @@
 pub mod fs;
+pub mod net;
 pub mod os;
-
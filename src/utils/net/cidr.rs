@@ -0,0 +1,129 @@
+use std::io;
+use std::net::IpAddr;
+
+/// An IPv4 or IPv6 network in CIDR notation.
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Parse `addr/len`, or a bare address as a single-host network.
+    pub fn parse(value: &str) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid CIDR '{value}'"));
+
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+
+        let network: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix {
+            Some(p) => p.trim().parse::<u8>().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+
+        Ok(Self { network, prefix_len })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // Let IPv4 rules match IPv4-mapped IPv6 addresses as well.
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            v4 => v4,
+        };
+
+        match (self.network, addr) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net) as u128, u32::from(ip) as u128, 32, self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: u128, addr: u128, bits: u8, prefix_len: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = bits - prefix_len;
+    (network >> shift) == (addr >> shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_networks_and_bare_addresses() {
+        assert!(Cidr::parse("10.0.0.0/8").unwrap().contains(ip("10.255.0.1")));
+        assert!(Cidr::parse(" 192.168.1.0 / 24 ").unwrap().contains(ip("192.168.1.77")));
+        assert!(Cidr::parse("2001:db8::/32").unwrap().contains(ip("2001:db8:ffff::1")));
+
+        let host = Cidr::parse("10.1.2.3").unwrap();
+        assert!(host.contains(ip("10.1.2.3")));
+        assert!(!host.contains(ip("10.1.2.4")));
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for value in ["", "10.0.0", "10.0.0.0/", "10.0.0.0/x", "10.0.0.0/-1", "10.0.0.0/33", "::/129", "::/256", "host.example/8", "10.0.0.0/8/8"] {
+            assert!(Cidr::parse(value).is_err(), "{value} parsed");
+        }
+    }
+
+    #[test]
+    fn zero_prefix_matches_its_whole_family() {
+        let v4 = Cidr::parse("0.0.0.0/0").unwrap();
+        assert!(v4.contains(ip("1.2.3.4")));
+        assert!(v4.contains(ip("255.255.255.255")));
+        assert!(!v4.contains(ip("2001:db8::1")));
+
+        let v6 = Cidr::parse("::/0").unwrap();
+        assert!(v6.contains(ip("2001:db8::1")));
+        assert!(!v6.contains(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn full_prefix_matches_one_address() {
+        let v4 = Cidr::parse("192.0.2.1/32").unwrap();
+        assert!(v4.contains(ip("192.0.2.1")));
+        assert!(!v4.contains(ip("192.0.2.0")));
+
+        let v6 = Cidr::parse("2001:db8::1/128").unwrap();
+        assert!(v6.contains(ip("2001:db8::1")));
+        assert!(!v6.contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn host_bits_in_the_network_are_ignored() {
+        let cidr = Cidr::parse("10.1.2.3/16").unwrap();
+        assert!(cidr.contains(ip("10.1.200.1")));
+        assert!(!cidr.contains(ip("10.2.0.1")));
+    }
+
+    #[test]
+    fn families_do_not_mix() {
+        assert!(!Cidr::parse("10.0.0.0/8").unwrap().contains(ip("::a00:1")));
+        assert!(!Cidr::parse("::/96").unwrap().contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn ipv4_rules_match_ipv4_mapped_addresses() {
+        let cidr = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(ip("::ffff:11.1.2.3")));
+    }
+}
@@ -1,2 +1,9 @@
 pub mod os_info;
 pub mod os_sandbox;
+pub mod os_cgroup;
+pub mod os_landlock;
+pub mod os_namespace;
+pub mod os_mount;
+pub mod os_storage;
+pub mod os_process;
+pub mod os_probe;
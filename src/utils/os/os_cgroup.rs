@@ -0,0 +1,194 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use log::debug;
+use nix::unistd::Uid;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+pub const CONTROLLERS: [&str; 4] = ["memory", "cpu", "pids", "io"];
+const CPU_PERIOD_USEC: u64 = 100_000;
+
+#[derive(Debug, Default)]
+pub struct CgroupLimits {
+    pub memory_max: Option<u64>,
+    pub cpus: Option<f64>,
+    pub pids_max: Option<u64>,
+    /// Raw `io.max` lines, e.g. `8:0 rbps=1048576 wiops=100`.
+    pub io_max: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CgroupStats {
+    pub memory_peak: Option<u64>,
+    pub cpu_usage_usec: Option<u64>,
+    pub cpu_user_usec: Option<u64>,
+    pub cpu_system_usec: Option<u64>,
+    pub oom_events: u64,
+    pub oom_kills: u64,
+    /// Forks refused because `pids.max` was reached.
+    pub pids_max_events: u64,
+}
+
+/// A per-invocation cgroup v2 directory. The directory is removed on drop.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+/// Whether a unified (v2) hierarchy is mounted at `/sys/fs/cgroup`.
+pub fn is_available() -> bool {
+    Path::new(CGROUP_ROOT).join("cgroup.controllers").exists()
+}
+
+/// Default parent: `/sys/fs/cgroup/zephir` for root, the systemd-delegated user
+/// service subtree otherwise.
+pub fn default_parent(is_root: bool) -> PathBuf {
+    if is_root {
+        return Path::new(CGROUP_ROOT).join("zephir");
+    }
+
+    let uid = Uid::current();
+    Path::new(CGROUP_ROOT)
+        .join("user.slice")
+        .join(format!("user-{uid}.slice"))
+        .join(format!("user@{uid}.service"))
+        .join("zephir.slice")
+}
+
+fn write_value(path: &Path, value: &str) -> io::Result<()> {
+    fs::write(path, value)
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to write '{}' to {}: {e}", value.trim(), path.display())))
+}
+
+fn enable_controllers(parent: &Path) {
+    let available = fs::read_to_string(parent.join("cgroup.controllers")).unwrap_or_default();
+    let subtree_control = parent.join("cgroup.subtree_control");
+
+    for controller in CONTROLLERS {
+        if !available.split_whitespace().any(|c| c == controller) {
+            debug!("cgroup controller '{}' not available in {}", controller, parent.display());
+            continue;
+        }
+        if let Err(e) = write_value(&subtree_control, &format!("+{controller}")) {
+            debug!("Could not enable cgroup controller '{}': {}", controller, e);
+        }
+    }
+}
+
+fn read_keyed(path: &Path, key: &str) -> Option<u64> {
+    fs::read_to_string(path).ok()?
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(k, _)| *k == key)
+        .and_then(|(_, v)| v.trim().parse().ok())
+}
+
+impl Cgroup {
+    /// Create `<parent>/<name>`, creating the parent and enabling the controllers
+    /// Zephir needs in its `cgroup.subtree_control` when necessary.
+    pub fn create(parent: &Path, name: &str) -> io::Result<Self> {
+        fs::create_dir_all(parent)?;
+        enable_controllers(parent);
+
+        let path = parent.join(name);
+        fs::create_dir(&path)?;
+
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn apply(&self, limits: &CgroupLimits) -> io::Result<()> {
+        if let Some(memory) = limits.memory_max {
+            write_value(&self.path.join("memory.max"), &memory.to_string())?;
+
+            // Without this the kernel swaps instead of enforcing memory.max.
+            let swap_max = self.path.join("memory.swap.max");
+            if swap_max.exists() {
+                write_value(&swap_max, "0")?;
+            }
+        }
+
+        if let Some(cpus) = limits.cpus {
+            let quota = ((cpus * CPU_PERIOD_USEC as f64) as u64).max(1_000);
+            write_value(&self.path.join("cpu.max"), &format!("{quota} {CPU_PERIOD_USEC}"))?;
+        }
+
+        if let Some(pids) = limits.pids_max {
+            write_value(&self.path.join("pids.max"), &pids.to_string())?;
+        }
+
+        for line in &limits.io_max {
+            write_value(&self.path.join("io.max"), line)?;
+        }
+
+        Ok(())
+    }
+
+    /// Open `cgroup.procs` so a forked child can join via [`join_from_child`].
+    pub fn open_procs(&self) -> io::Result<File> {
+        OpenOptions::new().write(true).open(self.path.join("cgroup.procs"))
+    }
+
+    /// Pids of the processes in the cgroup.
+    pub fn pids(&self) -> io::Result<Vec<u32>> {
+        Ok(fs::read_to_string(self.path.join("cgroup.procs"))?
+            .lines()
+            .filter_map(|pid| pid.trim().parse().ok())
+            .collect())
+    }
+
+    /// SIGKILL every process in the cgroup, via `cgroup.kill` where the kernel has it.
+    pub fn kill(&self) -> io::Result<()> {
+        let kill_file = self.path.join("cgroup.kill");
+        if kill_file.exists() {
+            return write_value(&kill_file, "1");
+        }
+
+        for pid in self.pids()? {
+            let _ = nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), nix::sys::signal::Signal::SIGKILL);
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> CgroupStats {
+        let memory_peak = fs::read_to_string(self.path.join("memory.peak"))
+            .ok()
+            .and_then(|v| v.trim().parse().ok());
+        let cpu_stat = self.path.join("cpu.stat");
+        let memory_events = self.path.join("memory.events");
+
+        CgroupStats {
+            memory_peak,
+            cpu_usage_usec: read_keyed(&cpu_stat, "usage_usec"),
+            cpu_user_usec: read_keyed(&cpu_stat, "user_usec"),
+            cpu_system_usec: read_keyed(&cpu_stat, "system_usec"),
+            oom_events: read_keyed(&memory_events, "oom").unwrap_or(0),
+            oom_kills: read_keyed(&memory_events, "oom_kill").unwrap_or(0),
+            pids_max_events: read_keyed(&self.path.join("pids.events"), "max").unwrap_or(0),
+        }
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir(&self.path) {
+            debug!("Failed to remove cgroup {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Move the calling process into the cgroup whose `cgroup.procs` is open on `procs_fd`.
+///
+/// Meant to run between fork and exec, so it only issues a raw `write(2)`.
+pub fn join_from_child(procs_fd: RawFd) -> io::Result<()> {
+    // Writing "0" moves the writing process itself.
+    let written = unsafe { libc::write(procs_fd, b"0".as_ptr().cast(), 1) };
+    if written != 1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use landlock::{
+    Access, AccessFs, PathBeneath, PathFd, Ruleset, RulesetAttr, RulesetCreated,
+    RulesetCreatedAttr, ABI,
+};
+
+/// Newest ABI Zephir knows about; older kernels get a best-effort subset.
+const TARGET_ABI: ABI = ABI::V5;
+
+/// First ABI able to restrict TCP connect/bind.
+pub const NETWORK_ABI: i32 = 4;
+
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+
+/// Landlock ABI version supported by the running kernel, or `None` when
+/// Landlock is missing or disabled.
+pub fn abi_version() -> Option<i32> {
+    let version = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<libc::c_void>(),
+            0usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    (version > 0).then_some(version as i32)
+}
+
+/// Build, but do not enforce, a ruleset granting full access beneath `sandbox_dir`
+/// and each of `write_paths`, and read/execute access beneath each of `read_paths`.
+pub fn build_ruleset(sandbox_dir: &Path, read_paths: &[PathBuf], write_paths: &[PathBuf]) -> io::Result<RulesetCreated> {
+    let all = AccessFs::from_all(TARGET_ABI);
+    let read = AccessFs::from_read(TARGET_ABI);
+
+    let mut ruleset = Ruleset::default()
+        .handle_access(all)
+        .and_then(|r| r.create())
+        .map_err(io::Error::other)?;
+
+    let sandbox_fd = PathFd::new(sandbox_dir)
+        .map_err(|e| io::Error::other(format!("landlock: cannot open {}: {e}", sandbox_dir.display())))?;
+    ruleset = ruleset.add_rule(PathBeneath::new(sandbox_fd, all)).map_err(io::Error::other)?;
+
+    let rules = read_paths.iter().map(|path| (path, read)).chain(write_paths.iter().map(|path| (path, all)));
+    for (path, access) in rules {
+        let path_fd = PathFd::new(path)
+            .map_err(|e| io::Error::other(format!("landlock: cannot open {}: {e}", path.display())))?;
+        ruleset = ruleset.add_rule(PathBeneath::new(path_fd, access)).map_err(io::Error::other)?;
+    }
+
+    Ok(ruleset)
+}
+
+/// Enforce a prepared ruleset on the calling process. Fork-safe: only issues
+/// `prctl(2)` and `landlock_restrict_self(2)`.
+pub fn restrict_self(ruleset: RulesetCreated) -> io::Result<()> {
+    ruleset.restrict_self().map_err(|_| io::Error::last_os_error())?;
+    Ok(())
+}
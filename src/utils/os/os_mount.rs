@@ -0,0 +1,375 @@
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+/// A mount to set up inside the function's mount namespace.
+#[derive(Debug, Clone)]
+pub enum MountSpec {
+    /// Bind `source` from the host onto `target`, relative to the sandbox.
+    Bind { source: PathBuf, target: PathBuf, read_only: bool },
+    /// A fresh tmpfs on `target`, relative to the sandbox, owned by `uid`/`gid`.
+    Tmpfs { target: PathBuf, size: Option<u64>, uid: u32, gid: u32 },
+}
+
+/// A mount point below the sandbox, reached one component at a time without
+/// following symlinks.
+#[derive(Debug)]
+pub struct MountPoint {
+    names: Vec<CString>,
+    /// A regular file rather than a directory.
+    file: bool,
+}
+
+impl MountPoint {
+    /// Open the mount point from `dir`, along with its parent directory (`None`
+    /// when that is `dir` itself). Creates nothing. Fork-safe.
+    fn open_from(&self, dir: RawFd) -> io::Result<(Option<OwnedFd>, OwnedFd)> {
+        let (name, names) = self.names.split_last().expect("a mount point has a name");
+        let mut parent: Option<OwnedFd> = None;
+        for component in names {
+            parent = Some(open_at(parent.as_ref().map_or(dir, |fd| fd.as_raw_fd()), component, libc::O_PATH | libc::O_DIRECTORY)?);
+        }
+        let flags = if self.file { libc::O_PATH } else { libc::O_PATH | libc::O_DIRECTORY };
+        let target = open_at(parent.as_ref().map_or(dir, |fd| fd.as_raw_fd()), name, flags)?;
+        Ok((parent, target))
+    }
+}
+
+/// A mount with everything `mount(2)` needs computed ahead of the fork.
+#[derive(Debug)]
+pub struct PreparedMount {
+    source: CString,
+    /// Opened again inside the new mount namespace, from the sandbox, and
+    /// mounted on through `/proc/self/fd`.
+    target: MountPoint,
+    fstype: Option<CString>,
+    flags: libc::c_ulong,
+    data: Option<CString>,
+    /// Flags for the read-only remount a bind mount needs, since `MS_RDONLY` is
+    /// ignored on the initial bind.
+    remount_flags: Option<libc::c_ulong>,
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))
+}
+
+fn open_at(dir: RawFd, name: &CStr, flags: libc::c_int) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::openat(dir, name.as_ptr(), flags | libc::O_CLOEXEC | libc::O_NOFOLLOW, 0o644) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Open the directory `name` in `dir`, creating it if it is missing.
+fn open_dir_at(dir: RawFd, name: &CStr) -> io::Result<OwnedFd> {
+    let flags = libc::O_PATH | libc::O_DIRECTORY;
+    match open_at(dir, name, flags) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if unsafe { libc::mkdirat(dir, name.as_ptr(), 0o755) } != 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::AlreadyExists {
+                    return Err(e);
+                }
+            }
+            open_at(dir, name, flags)
+        }
+        opened => opened,
+    }
+}
+
+/// Open the regular file `name` in `dir`, creating it empty if it is missing.
+fn open_file_at(dir: RawFd, name: &CStr) -> io::Result<OwnedFd> {
+    match open_at(dir, name, libc::O_PATH) {
+        Ok(fd) => {
+            let metadata = fs::File::from(fd.try_clone()?).metadata()?;
+            if !metadata.is_file() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a regular file"));
+            }
+            Ok(fd)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            drop(open_at(dir, name, libc::O_RDONLY | libc::O_CREAT | libc::O_EXCL)?);
+            open_at(dir, name, libc::O_PATH)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn target_names(relative: &Path) -> io::Result<Vec<CString>> {
+    let names = relative.components().map(|component| match component {
+        Component::Normal(name) => c_path(Path::new(name)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("mount target {} must be a path inside the sandbox", relative.display()))),
+    }).collect::<io::Result<Vec<_>>>()?;
+    if names.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "mount target must not be the sandbox itself"));
+    }
+    Ok(names)
+}
+
+fn refused(relative: &Path, e: io::Error) -> io::Error {
+    match e.raw_os_error() {
+        Some(libc::ELOOP | libc::ENOTDIR) => io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("mount target {}: a path component is a symlink or not a directory", relative.display()),
+        ),
+        _ => io::Error::new(e.kind(), format!("mount target {}: {e}", relative.display())),
+    }
+}
+
+/// Walk `relative` below `root` one component at a time, never following a
+/// symlink, and create what is missing: directories, and the last component as
+/// an empty file when `file` is set. `root` is trusted; what is below it is the
+/// unpacked package, where a symlink could otherwise point the mount point, or
+/// the directories created for it, anywhere on the host.
+pub fn create_mount_point(root: &Path, relative: &Path, file: bool) -> io::Result<MountPoint> {
+    let names = target_names(relative)?;
+    let (name, components) = names.split_last().expect("target_names is never empty");
+
+    let mut parent: OwnedFd = fs::File::open(root)?.into();
+    for component in components {
+        parent = open_dir_at(parent.as_raw_fd(), component).map_err(|e| refused(relative, e))?;
+    }
+    match file {
+        true => open_file_at(parent.as_raw_fd(), name),
+        false => open_dir_at(parent.as_raw_fd(), name),
+    }.map_err(|e| refused(relative, e))?;
+    Ok(MountPoint { names, file })
+}
+
+/// Like [`create_mount_point`] for a directory, but only checks the components
+/// that exist and creates nothing.
+pub fn check_mount_point(root: &Path, relative: &Path) -> io::Result<()> {
+    let mut dir: OwnedFd = fs::File::open(root)?.into();
+    for name in target_names(relative)? {
+        match open_at(dir.as_raw_fd(), &name, libc::O_PATH | libc::O_DIRECTORY) {
+            Ok(next) => dir = next,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(refused(relative, e)),
+        }
+    }
+    Ok(())
+}
+
+/// Flags of the filesystem holding `path` that a remount in a user namespace must keep.
+fn locked_flags(path: &CString) -> io::Result<libc::c_ulong> {
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut flags = 0;
+    for (st, ms) in [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ] {
+        if stat.f_flag & st != 0 {
+            flags |= ms;
+        }
+    }
+    Ok(flags)
+}
+
+/// Check the mount and create its mount point below `sandbox`.
+pub fn prepare(sandbox: &Path, spec: &MountSpec) -> io::Result<PreparedMount> {
+    match spec {
+        MountSpec::Bind { source, target, read_only } => {
+            let metadata = fs::metadata(source)
+                .map_err(|e| io::Error::new(e.kind(), format!("mount source {}: {e}", source.display())))?;
+            let target = create_mount_point(sandbox, target, !metadata.is_dir())?;
+
+            let source = c_path(source)?;
+            let remount_flags = match read_only {
+                true => Some(libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | locked_flags(&source)?),
+                false => None,
+            };
+            Ok(PreparedMount {
+                source,
+                target,
+                fstype: None,
+                flags: libc::MS_BIND | libc::MS_REC,
+                data: None,
+                remount_flags,
+            })
+        }
+        MountSpec::Tmpfs { target, size, uid, gid } => {
+            let target = create_mount_point(sandbox, target, false)?;
+
+            let mut data = format!("mode=0755,uid={uid},gid={gid}");
+            if let Some(size) = size {
+                data.push_str(&format!(",size={size}"));
+            }
+            Ok(PreparedMount {
+                source: CString::from(c"tmpfs"),
+                target,
+                fstype: Some(CString::from(c"tmpfs")),
+                flags: libc::MS_NOSUID | libc::MS_NODEV,
+                data: Some(CString::new(data).expect("mount options contain no NUL")),
+                remount_flags: None,
+            })
+        }
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+/// `/proc/self/fd/<fd>`, NUL-terminated and built without allocating.
+struct FdPath([u8; 32]);
+
+impl FdPath {
+    fn new(fd: RawFd) -> Self {
+        const PREFIX: &[u8] = b"/proc/self/fd/";
+        let mut path = [0; 32];
+        path[..PREFIX.len()].copy_from_slice(PREFIX);
+        let mut digits = [0; 10];
+        let (mut fd, mut count) = (fd as u32, 0);
+        loop {
+            digits[count] = b'0' + (fd % 10) as u8;
+            count += 1;
+            fd /= 10;
+            if fd == 0 {
+                break;
+            }
+        }
+        for (i, digit) in digits[..count].iter().rev().enumerate() {
+            path[PREFIX.len() + i] = *digit;
+        }
+        Self(path)
+    }
+
+    fn as_ptr(&self) -> *const libc::c_char {
+        self.0.as_ptr().cast()
+    }
+}
+
+/// Set up `mounts` in the calling process, which must have just entered a new
+/// mount namespace with the sandbox as its working directory. Fork-safe.
+pub fn apply(mounts: &[PreparedMount]) -> io::Result<()> {
+    let null = std::ptr::null::<libc::c_char>();
+    unsafe {
+        // Keep our mounts from propagating back to the host.
+        check(libc::mount(null, c"/".as_ptr(), null, libc::MS_REC | libc::MS_PRIVATE, std::ptr::null()))?;
+
+        for mount in mounts {
+            // Fds from before the unshare belong to the old namespace, where mounting is refused.
+            let (parent, target) = mount.target.open_from(libc::AT_FDCWD)?;
+            check(libc::mount(
+                mount.source.as_ptr(),
+                FdPath::new(target.as_raw_fd()).as_ptr(),
+                mount.fstype.as_ref().map_or(null, |t| t.as_ptr()),
+                mount.flags,
+                mount.data.as_ref().map_or(std::ptr::null(), |d| d.as_ptr().cast()),
+            ))?;
+            if let Some(flags) = mount.remount_flags {
+                // The fd still refers to what is under the new mount; looking the
+                // name up again from the parent lands on the mount itself.
+                let name = mount.target.names.last().expect("a mount point has a name");
+                let mounted = open_at(parent.as_ref().map_or(libc::AT_FDCWD, |fd| fd.as_raw_fd()), name, libc::O_PATH)?;
+                check(libc::mount(null, FdPath::new(mounted.as_raw_fd()).as_ptr(), null, flags, std::ptr::null()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// A fresh directory holding a `sandbox` and, next to it, an `outside` one.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zephir-mount-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sandbox")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        dir
+    }
+
+    fn is_refused(result: io::Result<impl std::fmt::Debug>) -> bool {
+        result.expect_err("a symlinked mount target was accepted").kind() == io::ErrorKind::InvalidInput
+    }
+
+    #[test]
+    fn missing_directories_and_files_are_created() {
+        let dir = scratch("create");
+        let sandbox = dir.join("sandbox");
+        create_mount_point(&sandbox, Path::new("a/b/c"), false).unwrap();
+        assert!(sandbox.join("a/b/c").is_dir());
+        create_mount_point(&sandbox, Path::new("a/file"), true).unwrap();
+        assert!(sandbox.join("a/file").is_file());
+        // Existing ones are reused.
+        create_mount_point(&sandbox, Path::new("a/b/c"), false).unwrap();
+        create_mount_point(&sandbox, Path::new("a/file"), true).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn symlinked_components_are_refused_before_anything_is_created() {
+        let dir = scratch("symlink");
+        let sandbox = dir.join("sandbox");
+        symlink(dir.join("outside"), sandbox.join("data")).unwrap();
+
+        assert!(is_refused(create_mount_point(&sandbox, Path::new("data/nologin"), true)));
+        assert!(is_refused(create_mount_point(&sandbox, Path::new("data/sub/dir"), false)));
+        assert!(is_refused(create_mount_point(&sandbox, Path::new("data"), false)));
+        assert!(is_refused(check_mount_point(&sandbox, Path::new("data/sub"))));
+        assert_eq!(fs::read_dir(dir.join("outside")).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_symlinked_file_target_is_refused() {
+        let dir = scratch("file");
+        let sandbox = dir.join("sandbox");
+        symlink(dir.join("outside/nologin"), sandbox.join("nologin")).unwrap();
+
+        assert!(is_refused(create_mount_point(&sandbox, Path::new("nologin"), true)));
+        assert!(!dir.join("outside/nologin").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reopening_does_not_follow_a_symlink_swapped_in_later() {
+        let dir = scratch("swap");
+        let sandbox = dir.join("sandbox");
+        let point = create_mount_point(&sandbox, Path::new("data/dir"), false).unwrap();
+        let root: OwnedFd = fs::File::open(&sandbox).unwrap().into();
+        assert!(point.open_from(root.as_raw_fd()).is_ok());
+
+        fs::remove_dir_all(sandbox.join("data")).unwrap();
+        fs::create_dir(dir.join("outside/dir")).unwrap();
+        symlink(dir.join("outside"), sandbox.join("data")).unwrap();
+        assert_eq!(point.open_from(root.as_raw_fd()).unwrap_err().raw_os_error(), Some(libc::ENOTDIR));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn targets_must_stay_inside_the_sandbox() {
+        let dir = scratch("escape");
+        let sandbox = dir.join("sandbox");
+        assert!(is_refused(create_mount_point(&sandbox, Path::new("../outside/x"), false)));
+        assert!(is_refused(create_mount_point(&sandbox, Path::new(""), false)));
+        assert!(check_mount_point(&sandbox, Path::new("missing/dir")).is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn fd_paths_name_the_fd() {
+        for fd in [0, 7, 42, 1_000_000] {
+            let path = FdPath::new(fd);
+            let path = unsafe { CStr::from_ptr(path.as_ptr()) };
+            assert_eq!(path.to_str().unwrap(), format!("/proc/self/fd/{fd}"));
+        }
+    }
+}
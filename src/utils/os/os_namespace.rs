@@ -0,0 +1,265 @@
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::process::Command;
+use std::thread;
+use log::debug;
+use nix::sched::{unshare, CloneFlags};
+use nix::unistd::{Gid, Uid};
+
+use crate::utils::os::os_mount;
+
+/// `count` ids starting at `inside` in the namespace map to ids starting at `outside` on the host.
+#[derive(Debug, Clone, Copy)]
+pub struct IdMapping {
+    pub inside: u32,
+    pub outside: u32,
+    pub count: u32,
+}
+
+impl IdMapping {
+    fn render(mappings: &[IdMapping]) -> String {
+        mappings.iter().map(|m| format!("{} {} {}\n", m.inside, m.outside, m.count)).collect()
+    }
+
+    /// Host id that `inside` maps to, if any.
+    pub fn to_host(mappings: &[IdMapping], inside: u32) -> Option<u32> {
+        mappings
+            .iter()
+            .find(|m| inside >= m.inside && inside - m.inside < m.count)
+            .map(|m| m.outside + (inside - m.inside))
+    }
+}
+
+/// uid/gid maps for a new user namespace.
+#[derive(Debug, Clone)]
+pub struct IdMaps {
+    pub uid_map: Vec<IdMapping>,
+    pub gid_map: Vec<IdMapping>,
+    /// Deny `setgroups(2)` in the namespace, which the kernel requires before an
+    /// unprivileged process may write a gid map.
+    pub deny_setgroups: bool,
+}
+
+impl IdMaps {
+    /// Map the caller's own uid and gid onto themselves, the only mapping an
+    /// unprivileged process may write without helpers.
+    pub fn identity() -> Self {
+        let (uid, gid) = (Uid::current().as_raw(), Gid::current().as_raw());
+        Self {
+            uid_map: vec![IdMapping { inside: uid, outside: uid, count: 1 }],
+            gid_map: vec![IdMapping { inside: gid, outside: gid, count: 1 }],
+            deny_setgroups: true,
+        }
+    }
+
+    /// Whether the maps only cover the caller's own ids, which it may write itself.
+    fn only_own_ids(&self) -> bool {
+        let own = |maps: &[IdMapping], id: u32| matches!(maps, [m] if m.outside == id && m.count == 1);
+        own(&self.uid_map, Uid::current().as_raw()) && own(&self.gid_map, Gid::current().as_raw())
+    }
+
+    /// Write the maps for process `pid`. Root writes them directly, and so does an
+    /// unprivileged caller mapping only its own ids; anything else goes through the
+    /// setuid `newuidmap`/`newgidmap` helpers, which check `/etc/subuid` and `/etc/subgid`.
+    fn write_for(&self, pid: u32) -> io::Result<()> {
+        let proc_dir = format!("/proc/{pid}");
+
+        if Uid::current().is_root() || self.only_own_ids() {
+            if self.deny_setgroups {
+                fs::write(format!("{proc_dir}/setgroups"), "deny")?;
+            }
+            fs::write(format!("{proc_dir}/uid_map"), IdMapping::render(&self.uid_map))?;
+            fs::write(format!("{proc_dir}/gid_map"), IdMapping::render(&self.gid_map))?;
+            return Ok(());
+        }
+
+        run_map_helper("newuidmap", pid, &self.uid_map)?;
+        run_map_helper("newgidmap", pid, &self.gid_map)
+    }
+}
+
+fn run_map_helper(helper: &str, pid: u32, mappings: &[IdMapping]) -> io::Result<()> {
+    let mut command = Command::new(helper);
+    command.arg(pid.to_string());
+    for m in mappings {
+        command.arg(m.inside.to_string()).arg(m.outside.to_string()).arg(m.count.to_string());
+    }
+
+    let output = command.output().map_err(|e| io::Error::new(e.kind(), format!("failed to run {helper}: {e}")))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{helper} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Child side of the handshake with [`IdMapWriter`]: fds inherited across fork.
+#[derive(Debug)]
+pub struct IdMapSync {
+    pid_fd: RawFd,
+    ready_fd: RawFd,
+}
+
+/// Writes a child's uid/gid maps from the parent, because a process that just
+/// entered a user namespace has no privileges left to map anything but its own ids.
+///
+/// The child sends its pid once it has unshared and waits for a status byte; a
+/// thread here writes the maps and answers. Keep the writer alive until the child
+/// has been spawned: dropping it closes the child's ends of the pipes, so the
+/// thread sees EOF and exits if the child died early.
+pub struct IdMapWriter {
+    _child_ends: (OwnedFd, OwnedFd),
+}
+
+const MAPS_WRITTEN: u8 = 1;
+const MAPS_FAILED: u8 = 0;
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: pipe2 just returned these fds and nothing else owns them.
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+impl IdMapWriter {
+    pub fn start(maps: IdMaps) -> io::Result<(Self, IdMapSync)> {
+        let (pid_read, pid_write) = pipe()?;
+        let (ready_read, ready_write) = pipe()?;
+
+        thread::Builder::new()
+            .name("zephir-idmap".to_string())
+            .spawn(move || {
+                let mut pid = [0u8; 4];
+                // EOF: the child died (or never unshared) before asking for its maps.
+                if read_exact_fd(pid_read.as_raw_fd(), &mut pid).is_err() {
+                    return;
+                }
+                let pid = u32::from_ne_bytes(pid);
+
+                let status = match maps.write_for(pid) {
+                    Ok(()) => MAPS_WRITTEN,
+                    Err(e) => {
+                        debug!("Failed to write id maps for {}: {}", pid, e);
+                        MAPS_FAILED
+                    }
+                };
+                unsafe { libc::write(ready_write.as_raw_fd(), [status].as_ptr().cast(), 1) };
+            })?;
+
+        let sync = IdMapSync { pid_fd: pid_write.as_raw_fd(), ready_fd: ready_read.as_raw_fd() };
+        Ok((Self { _child_ends: (pid_write, ready_read) }, sync))
+    }
+}
+
+fn read_exact_fd(fd: RawFd, buf: &mut [u8]) -> io::Result<()> {
+    let mut read = 0;
+    while read < buf.len() {
+        let n = unsafe { libc::read(fd, buf[read..].as_mut_ptr().cast(), buf.len() - read) };
+        match n {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n if n < 0 => {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            }
+            n => read += n as usize,
+        }
+    }
+    Ok(())
+}
+
+impl IdMapSync {
+    /// Ask the parent for our maps and wait until they are written. Fork-safe.
+    fn wait_for_maps(&self) -> io::Result<()> {
+        let pid = (unsafe { libc::getpid() } as u32).to_ne_bytes();
+        if unsafe { libc::write(self.pid_fd, pid.as_ptr().cast(), pid.len()) } != pid.len() as isize {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut status = [MAPS_FAILED];
+        read_exact_fd(self.ready_fd, &mut status)?;
+        if status[0] != MAPS_WRITTEN {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "parent failed to write the user namespace id maps"));
+        }
+        Ok(())
+    }
+}
+
+/// Namespaces the child unshares before anything else is locked down.
+#[derive(Debug, Default)]
+pub struct NamespacePlan {
+    /// Also create a user namespace, whose maps the parent writes through this handshake.
+    pub user: Option<IdMapSync>,
+    pub network: bool,
+    /// Bring `lo` up inside the new network namespace.
+    pub loopback: bool,
+    /// Mounts to set up in a new mount namespace.
+    pub mounts: Vec<os_mount::PreparedMount>,
+}
+
+fn bring_up_loopback() -> io::Result<()> {
+    unsafe {
+        let sock = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if sock < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut req: libc::ifreq = std::mem::zeroed();
+        for (dst, src) in req.ifr_name.iter_mut().zip(b"lo\0") {
+            *dst = *src as libc::c_char;
+        }
+
+        let mut result = libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut req);
+        if result == 0 {
+            req.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+            result = libc::ioctl(sock, libc::SIOCSIFFLAGS, &req);
+        }
+        let error = io::Error::last_os_error();
+        libc::close(sock);
+
+        if result != 0 {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// Unshare and set up the planned namespaces for the calling process. Fork-safe:
+/// it only issues syscalls on fds and data prepared before the fork.
+pub fn enter_namespaces(plan: &NamespacePlan) -> io::Result<()> {
+    let mut flags = CloneFlags::empty();
+    if plan.user.is_some() {
+        flags |= CloneFlags::CLONE_NEWUSER;
+    }
+    if plan.network {
+        flags |= CloneFlags::CLONE_NEWNET;
+    }
+    if !plan.mounts.is_empty() {
+        flags |= CloneFlags::CLONE_NEWNS;
+    }
+    if flags.is_empty() {
+        return Ok(());
+    }
+
+    unshare(flags).map_err(io::Error::from)?;
+
+    if let Some(sync) = &plan.user {
+        sync.wait_for_maps()?;
+    }
+
+    if plan.network && plan.loopback {
+        bring_up_loopback()?;
+    }
+
+    if !plan.mounts.is_empty() {
+        os_mount::apply(&plan.mounts)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use std::fs;
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use nix::unistd::{access, AccessFlags};
+use rlimit::Resource;
+use serde::Serialize;
+
+use crate::utils::os::{os_cgroup, os_landlock};
+
+/// Whether the host offers an isolation feature, with what was found or why it is missing.
+#[derive(Debug, Clone, Serialize)]
+pub struct Feature {
+    pub available: bool,
+    pub detail: String,
+}
+
+impl Feature {
+    fn available(detail: impl Into<String>) -> Self {
+        Self { available: true, detail: detail.into() }
+    }
+
+    fn missing(detail: impl Into<String>) -> Self {
+        Self { available: false, detail: detail.into() }
+    }
+}
+
+/// `path`, or its closest ancestor that exists.
+fn existing_ancestor(path: &Path) -> PathBuf {
+    path.ancestors()
+        .find(|dir| !dir.as_os_str().is_empty() && dir.exists())
+        .map_or_else(|| PathBuf::from("."), Path::to_path_buf)
+}
+
+fn read_sysctl(path: &str) -> Option<String> {
+    fs::read_to_string(path).ok().map(|value| value.trim().to_string())
+}
+
+/// Whether this process may create a user namespace.
+pub fn user_namespaces() -> Feature {
+    // unshare(CLONE_NEWUSER) is refused to multithreaded processes, so try it in a
+    // fork that only unshares and exits with the errno.
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Feature::missing(format!("could not fork to probe: {}", io::Error::last_os_error()));
+    }
+    if pid == 0 {
+        unsafe {
+            let code = if libc::unshare(libc::CLONE_NEWUSER) == 0 { 0 } else { nix::errno::Errno::last_raw() };
+            libc::_exit(code);
+        }
+    }
+
+    let mut status = 0;
+    if unsafe { libc::waitpid(pid, &mut status, 0) } != pid || !libc::WIFEXITED(status) {
+        return Feature::missing("the probe process did not exit cleanly");
+    }
+    match libc::WEXITSTATUS(status) {
+        0 => Feature::available("unshare(CLONE_NEWUSER) works"),
+        errno => {
+            let mut detail = io::Error::from_raw_os_error(errno).to_string();
+            if read_sysctl("/proc/sys/kernel/unprivileged_userns_clone").as_deref() == Some("0") {
+                detail.push_str("; kernel.unprivileged_userns_clone is 0");
+            }
+            if read_sysctl("/proc/sys/user/max_user_namespaces").as_deref() == Some("0") {
+                detail.push_str("; user.max_user_namespaces is 0");
+            }
+            if read_sysctl("/proc/sys/kernel/apparmor_restrict_unprivileged_userns").as_deref() == Some("1") {
+                detail.push_str("; AppArmor restricts unprivileged user namespaces");
+            }
+            Feature::missing(detail)
+        }
+    }
+}
+
+/// Whether Zephir can create cgroups under `parent`, and which of its controllers it gets there.
+pub fn cgroup_delegation(parent: &Path) -> Feature {
+    if !os_cgroup::is_available() {
+        return Feature::missing("no cgroup v2 hierarchy at /sys/fs/cgroup");
+    }
+
+    let dir = existing_ancestor(parent);
+    if access(&dir, AccessFlags::W_OK).is_err() {
+        return Feature::missing(format!("{} is not writable", dir.display()));
+    }
+
+    let controllers = fs::read_to_string(dir.join("cgroup.controllers")).unwrap_or_default();
+    let missing: Vec<&str> = os_cgroup::CONTROLLERS
+        .into_iter()
+        .filter(|wanted| !controllers.split_whitespace().any(|c| c == *wanted))
+        .collect();
+    match missing.is_empty() {
+        true => Feature::available(format!("{} ({})", parent.display(), controllers.trim())),
+        false => Feature::available(format!("{} (missing controllers: {})", parent.display(), missing.join(" "))),
+    }
+}
+
+/// Whether the kernel supports seccomp filters.
+pub fn seccomp() -> Feature {
+    let mode = unsafe { libc::prctl(libc::PR_GET_SECCOMP) };
+    if mode < 0 {
+        return Feature::missing("kernel built without seccomp");
+    }
+
+    // A NULL filter fails with EFAULT exactly when filter mode is supported, and installs nothing.
+    let result = unsafe { libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER as libc::c_ulong, 0 as libc::c_ulong) };
+    if result == 0 || nix::errno::Errno::last() != nix::errno::Errno::EFAULT {
+        return Feature::missing("kernel supports strict mode only");
+    }
+    match mode {
+        0 => Feature::available("filter mode"),
+        _ => Feature::available("filter mode; Zephir itself already runs under a filter"),
+    }
+}
+
+pub fn landlock() -> Feature {
+    match os_landlock::abi_version() {
+        Some(version) if version >= os_landlock::NETWORK_ABI => Feature::available(format!("ABI {version}, with TCP port rules")),
+        Some(version) => Feature::available(format!("ABI {version}, filesystem only")),
+        None => Feature::missing("not supported or disabled by the kernel"),
+    }
+}
+
+pub fn overlayfs() -> Feature {
+    let filesystems = fs::read_to_string("/proc/filesystems").unwrap_or_default();
+    if filesystems.lines().any(|line| line.split_whitespace().last() == Some("overlay")) {
+        Feature::available("listed in /proc/filesystems")
+    } else {
+        Feature::missing("not in /proc/filesystems (the overlay module may not be loaded)")
+    }
+}
+
+/// Whether the filesystem holding `dir` (or its closest existing ancestor) can clone files with `FICLONE`.
+pub fn reflink(dir: &Path) -> Feature {
+    let dir = existing_ancestor(dir);
+    let source_path = dir.join(format!(".zephir-reflink-{}", std::process::id()));
+    let clone_path = dir.join(format!(".zephir-reflink-{}.clone", std::process::id()));
+
+    let cloned = (|| {
+        // FICLONE reads the source, so it cannot be opened write-only.
+        let mut source = fs::OpenOptions::new().read(true).write(true).create_new(true).open(&source_path)?;
+        source.write_all(&[0; 4096])?;
+        source.sync_all()?;
+        let clone = fs::File::create(&clone_path)?;
+        if unsafe { libc::ioctl(clone.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    })();
+    let _ = fs::remove_file(&source_path);
+    let _ = fs::remove_file(&clone_path);
+
+    match cloned {
+        Ok(()) => Feature::available(dir.display().to_string()),
+        Err(e) => Feature::missing(format!("{}: {e}", dir.display())),
+    }
+}
+
+/// Hard limit of `resource` for this process; `None` when unlimited or unknown.
+pub fn hard_limit(resource: Resource) -> Option<u64> {
+    rlimit::getrlimit(resource)
+        .ok()
+        .map(|(_, hard)| hard)
+        .filter(|&hard| hard != rlimit::INFINITY)
+}
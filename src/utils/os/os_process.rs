@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+use log::debug;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+
+use crate::utils::os::os_cgroup::Cgroup;
+
+/// How long [`ProcessTree::shutdown`] waits for killed processes to be reaped.
+const REAP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Send `signal` to every process in the group `pgid`. A group that is already
+/// gone is not an error.
+pub fn signal_group(pgid: u32, signal: Signal) {
+    if let Err(e) = killpg(Pid::from_raw(pgid as i32), signal) {
+        debug!("killpg({}, {}) failed: {}", pgid, signal, e);
+    }
+}
+
+/// Start a new session (and process group) for the calling process. Fork-safe.
+pub fn start_session() -> io::Result<()> {
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Have orphaned descendants reparented to Zephir instead of init, so they can
+/// be reaped once the function is torn down.
+pub fn become_subreaper() -> io::Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Resource usage of reaped processes, from `wait4`: each one's own plus that of
+/// every descendant it waited for.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessUsage {
+    pub user_time_us: u64,
+    pub system_time_us: u64,
+    pub max_rss_kib: u64,
+    pub minor_faults: u64,
+    pub major_faults: u64,
+    pub block_input: u64,
+    pub block_output: u64,
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+}
+
+impl ProcessUsage {
+    fn from_rusage(usage: &libc::rusage) -> Self {
+        let micros = |tv: libc::timeval| tv.tv_sec as u64 * 1_000_000 + tv.tv_usec as u64;
+        Self {
+            user_time_us: micros(usage.ru_utime),
+            system_time_us: micros(usage.ru_stime),
+            max_rss_kib: usage.ru_maxrss as u64,
+            minor_faults: usage.ru_minflt as u64,
+            major_faults: usage.ru_majflt as u64,
+            block_input: usage.ru_inblock as u64,
+            block_output: usage.ru_oublock as u64,
+            voluntary_switches: usage.ru_nvcsw as u64,
+            involuntary_switches: usage.ru_nivcsw as u64,
+        }
+    }
+
+    /// Count `other` in as well. Max RSS is that of the largest process, so it is
+    /// the higher of the two rather than the sum.
+    pub fn add(&mut self, other: &Self) {
+        self.user_time_us += other.user_time_us;
+        self.system_time_us += other.system_time_us;
+        self.max_rss_kib = self.max_rss_kib.max(other.max_rss_kib);
+        self.minor_faults += other.minor_faults;
+        self.major_faults += other.major_faults;
+        self.block_input += other.block_input;
+        self.block_output += other.block_output;
+        self.voluntary_switches += other.voluntary_switches;
+        self.involuntary_switches += other.involuntary_switches;
+    }
+}
+
+/// Reap child `pid` if it has exited, without blocking. `None` while it still
+/// runs.
+fn try_wait(pid: i32) -> io::Result<Option<(ExitStatus, ProcessUsage)>> {
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        match unsafe { libc::wait4(pid, &mut status, libc::WNOHANG, &mut usage) } {
+            0 => return Ok(None),
+            -1 => {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            }
+            _ => return Ok(Some((ExitStatus::from_raw(status), ProcessUsage::from_rusage(&usage)))),
+        }
+    }
+}
+
+/// What runs in a session at one moment.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Census {
+    pub processes: u64,
+    /// All tasks: every process counts at least one thread.
+    pub threads: u64,
+    /// Open file descriptors of the process holding the most.
+    pub max_open_files: u64,
+    /// Tasks of the uid passed to [`census`], inside the session or not.
+    pub uid_threads: u64,
+}
+
+impl Census {
+    /// Keep the highest of each count.
+    pub fn raise_to(&mut self, other: &Census) {
+        self.processes = self.processes.max(other.processes);
+        self.threads = self.threads.max(other.threads);
+        self.max_open_files = self.max_open_files.max(other.max_open_files);
+        self.uid_threads = self.uid_threads.max(other.uid_threads);
+    }
+}
+
+/// Count the processes, threads and open files in session `sid` from `/proc`, and
+/// optionally all tasks of `uid`. Processes that vanish mid-scan are skipped.
+pub fn census(sid: u32, uid: Option<u32>) -> Census {
+    let mut census = Census::default();
+    let Ok(entries) = std::fs::read_dir("/proc") else { return census };
+
+    for entry in entries.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else { continue };
+        let Ok(stat) = std::fs::read_to_string(format!("/proc/{pid}/stat")) else { continue };
+        // Fields after the parenthesised command name, starting with the state (field 3).
+        let Some((_, fields)) = stat.rsplit_once(") ") else { continue };
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+        let threads = fields.get(17).and_then(|threads| threads.parse().ok()).unwrap_or(1);
+        if uid.is_some() && entry.metadata().ok().map(|metadata| metadata.uid()) == uid {
+            census.uid_threads += threads;
+        }
+        if fields.get(3).and_then(|session| session.parse::<u32>().ok()) != Some(sid) {
+            continue;
+        }
+
+        census.processes += 1;
+        census.threads += threads;
+        if let Ok(fds) = std::fs::read_dir(format!("/proc/{pid}/fd")) {
+            census.max_open_files = census.max_open_files.max(fds.count() as u64);
+        }
+    }
+    census
+}
+
+/// Pids of the children of `pid`, from `/proc/<pid>/task/*/children`.
+fn children_of(pid: u32) -> Vec<u32> {
+    let Ok(tasks) = std::fs::read_dir(format!("/proc/{pid}/task")) else { return Vec::new() };
+    tasks
+        .flatten()
+        .filter_map(|task| std::fs::read_to_string(task.path().join("children")).ok())
+        .flat_map(|children| children.split_whitespace().filter_map(|child| child.parse().ok()).collect::<Vec<u32>>())
+        .collect()
+}
+
+/// Session and process group of `pid`, from `/proc/<pid>/stat`.
+fn session_and_group(pid: u32) -> Option<(u32, u32)> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // Parent, group and session follow the state, after the parenthesised command name.
+    let mut fields = stat.rsplit_once(") ")?.1.split_whitespace().skip(2);
+    let group = fields.next()?.parse().ok()?;
+    let session = fields.next()?.parse().ok()?;
+    Some((session, group))
+}
+
+fn pidfd_open(pid: u32) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Send `signal` (`None` to only check that it exists) to the process behind `pidfd`.
+fn pidfd_signal(pidfd: &OwnedFd, signal: Option<Signal>) -> io::Result<()> {
+    let signal = signal.map_or(0, |signal| signal as libc::c_int);
+    let result = unsafe { libc::syscall(libc::SYS_pidfd_send_signal, pidfd.as_raw_fd(), signal, std::ptr::null::<libc::siginfo_t>(), 0) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Reap the process behind `pidfd` if it is a child of ours that has exited.
+fn pidfd_try_wait(pidfd: &OwnedFd) -> io::Result<Option<ProcessUsage>> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // The raw syscall, since only it returns the rusage.
+        let result = unsafe {
+            libc::syscall(libc::SYS_waitid, libc::P_PIDFD, pidfd.as_raw_fd(), &mut info, libc::WEXITED | libc::WNOHANG, &mut usage)
+        };
+        if result < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error);
+        }
+        return Ok((unsafe { info.si_pid() } != 0).then(|| ProcessUsage::from_rusage(&usage)));
+    }
+}
+
+/// Whether child `pid` has exited, leaving it to be reaped.
+fn has_exited(pid: u32) -> io::Result<bool> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    loop {
+        if unsafe { libc::waitid(libc::P_PID, pid, &mut info, libc::WEXITED | libc::WNOHANG | libc::WNOWAIT) } == 0 {
+            return Ok(unsafe { info.si_pid() } != 0);
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+/// Everything a function started: its session/process group, its cgroup when
+/// there is one, and every process seen in its subtree, held by pidfd so that
+/// a recycled pid is never signalled or reaped. Only these are touched, never
+/// other children of Zephir. [`ProcessTree::shutdown`] kills and reaps whatever
+/// is left; dropping the tree without it only kills.
+#[derive(Debug)]
+pub struct ProcessTree {
+    pgid: u32,
+    cgroup: Option<Cgroup>,
+    members: HashMap<u32, OwnedFd>,
+    /// The function itself was reaped, so its pid, and with it the session and
+    /// group id, may belong to someone else by now.
+    leader_reaped: bool,
+    shut_down: bool,
+}
+
+impl ProcessTree {
+    /// The tree of the function `pid`, which leads its own session. It must be a
+    /// child of ours that has not been reaped.
+    pub fn new(pid: u32, cgroup: Option<Cgroup>) -> io::Result<Self> {
+        let members = HashMap::from([(pid, pidfd_open(pid)?)]);
+        Ok(Self { pgid: pid, cgroup, members, leader_reaped: false, shut_down: false })
+    }
+
+    /// The session (and process group) id, which is the function's own pid.
+    pub fn session(&self) -> u32 {
+        self.pgid
+    }
+
+    pub fn cgroup(&self) -> Option<&Cgroup> {
+        self.cgroup.as_ref()
+    }
+
+    /// Add the processes the function started since the last call: children of
+    /// known members, anything in its cgroup and, while its session and group
+    /// id are still its own, the children of ours in them. The last are what
+    /// Zephir adopted as the subreaper when their parent exited; a process that
+    /// leaves both the session and its parent between two calls is only found
+    /// through the cgroup.
+    pub fn track(&mut self) {
+        let mut found: Vec<u32> = self.members.keys().flat_map(|&pid| children_of(pid)).collect();
+        if let Some(cgroup) = &self.cgroup {
+            found.extend(cgroup.pids().unwrap_or_default());
+        }
+        if !self.leader_reaped {
+            found.extend(children_of(std::process::id()).into_iter().filter(|&pid| {
+                session_and_group(pid).is_some_and(|(session, group)| session == self.pgid || group == self.pgid)
+            }));
+        }
+
+        while let Some(pid) = found.pop() {
+            if self.members.contains_key(&pid) {
+                continue;
+            }
+            // Gone already if this fails; its children were reparented and show up with their new parent.
+            if let Ok(pidfd) = pidfd_open(pid) {
+                self.members.insert(pid, pidfd);
+                found.extend(children_of(pid));
+            }
+        }
+    }
+
+    /// Send `signal` to every process in the tree.
+    pub fn signal(&mut self, signal: Signal) {
+        self.track();
+        if !self.leader_reaped {
+            signal_group(self.pgid, signal);
+        }
+        for (pid, pidfd) in &self.members {
+            if let Err(e) = pidfd_signal(pidfd, Some(signal)) {
+                debug!("Sending {} to {} failed: {}", signal, pid, e);
+            }
+        }
+    }
+
+    /// SIGKILL every process left in the tree, and the cgroup.
+    pub fn kill(&mut self) {
+        self.signal(Signal::SIGKILL);
+        if let Some(cgroup) = &self.cgroup
+            && let Err(e) = cgroup.kill()
+        {
+            debug!("Failed to kill cgroup {}: {}", cgroup.path().display(), e);
+        }
+    }
+
+    /// Reap the function if it has exited. What it left running is tracked first,
+    /// while its pid still pins the session and group id.
+    pub fn try_wait(&mut self) -> io::Result<Option<(ExitStatus, ProcessUsage)>> {
+        if self.leader_reaped || !has_exited(self.pgid)? {
+            return Ok(None);
+        }
+        self.track();
+        let reaped = try_wait(self.pgid as i32)?;
+        if reaped.is_some() {
+            self.leader_reaped = true;
+            self.members.remove(&self.pgid);
+        }
+        Ok(reaped)
+    }
+
+    /// Reap every member that has exited, adding up what they used, and forget
+    /// those that are gone. Members that are not our children are reaped by their
+    /// parent, or by us once that dies. `true` once none are left.
+    fn reap_exited(&mut self, usage: &mut ProcessUsage) -> bool {
+        self.members.retain(|pid, pidfd| match pidfd_try_wait(pidfd) {
+            Ok(Some(reaped)) => {
+                usage.add(&reaped);
+                false
+            }
+            Ok(None) => true,
+            Err(e) if e.raw_os_error() == Some(libc::ECHILD) => pidfd_signal(pidfd, None).is_ok(),
+            Err(e) => {
+                debug!("Reaping {} failed: {}", pid, e);
+                true
+            }
+        });
+        self.members.is_empty()
+    }
+
+    /// Kill and reap everything left, giving up after [`REAP_TIMEOUT`]. Returns
+    /// what the reaped processes used.
+    pub async fn shutdown(mut self) -> ProcessUsage {
+        self.shut_down = true;
+        let deadline = Instant::now() + REAP_TIMEOUT;
+        let mut usage = ProcessUsage::default();
+
+        loop {
+            self.kill();
+            if self.reap_exited(&mut usage) {
+                return usage;
+            }
+            if Instant::now() >= deadline {
+                debug!("Gave up reaping {} processes of group {}", self.members.len(), self.pgid);
+                return usage;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+impl Drop for ProcessTree {
+    fn drop(&mut self) {
+        if !self.shut_down {
+            self.kill();
+            // Reaping what is already gone is all that can be done without blocking.
+            self.reap_exited(&mut ProcessUsage::default());
+        }
+    }
+}
@@ -1,28 +1,202 @@
+use std::ffi::CString;
 use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
 use std::path::Path;
 use rlimit::{setrlimit, Resource};
-use nix::unistd::{chroot, setgid, setuid, Gid, Uid};
+use nix::unistd::{chdir, chroot};
+use landlock::RulesetCreated;
 
-pub fn apply_unix_sandbox(is_root: bool, chroot_dir: Option<&Path>, cpu_time: u64, memory_limit: u64, size_limit: u64) -> io::Result<()> {
-    // CPU time
-    let _ = setrlimit(Resource::CPU, cpu_time, cpu_time);
+use crate::utils::os::{os_landlock, os_namespace};
+
+/// Who the sandboxed process runs as once privileges are dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups; `None` leaves them alone (e.g. where `setgroups` is denied).
+    pub groups: Option<Vec<u32>>,
+}
+
+/// Everything the child needs to sandbox itself, resolved up front so that
+/// [`apply_unix_sandbox`] can run between fork and exec without allocating.
+#[derive(Debug)]
+pub struct SandboxPolicy {
+    pub is_root: bool,
+    pub chroot_dir: Option<CString>,
+    pub cpu_time: u64,
+    /// RLIMIT_AS; `None` for processes that reserve large address spaces (e.g. wasmtime).
+    pub memory_limit: Option<u64>,
+    pub size_limit: u64,
+    /// RLIMIT_NPROC, which counts every task of the function's real uid.
+    pub max_tasks: Option<u64>,
+    pub max_open_files: Option<u64>,
+    pub max_stack: Option<u64>,
+    /// Namespaces to unshare first, while the process can still write to `/proc/self`.
+    pub namespaces: Option<os_namespace::NamespacePlan>,
+    /// Prepared Landlock rulesets, each enforced as its own layer and consumed
+    /// when the policy is applied.
+    pub landlock: Vec<RulesetCreated>,
+    /// Identity to switch to last; `None` keeps the current one.
+    pub identity: Option<Identity>,
+}
+
+impl SandboxPolicy {
+    pub fn new(is_root: bool, chroot_dir: Option<&Path>, cpu_time: u64, memory_limit: Option<u64>, size_limit: u64) -> io::Result<Self> {
+        let chroot_dir = match chroot_dir {
+            // The child chdirs into the sandbox before the hook runs, so relative paths would not resolve.
+            Some(dir) => Some(CString::new(dir.canonicalize()?.as_os_str().as_bytes())?),
+            None => None,
+        };
+
+        Ok(Self {
+            is_root,
+            chroot_dir,
+            cpu_time,
+            memory_limit,
+            size_limit,
+            max_tasks: None,
+            max_open_files: None,
+            max_stack: None,
+            namespaces: None,
+            landlock: Vec::new(),
+            identity: None,
+        })
+    }
+
+    /// The uid whose tasks RLIMIT_NPROC will count, when it is enforced outside a user namespace.
+    pub fn nproc_uid(&self) -> Option<u32> {
+        let in_user_namespace = self.namespaces.as_ref().is_some_and(|plan| plan.user.is_some());
+        match (self.max_tasks, &self.identity) {
+            (Some(_), Some(identity)) if !in_user_namespace => Some(identity.uid),
+            _ => None,
+        }
+    }
+
+    /// Whether applying the policy moves the process into a chroot.
+    pub fn chroots(&self) -> bool {
+        self.is_root && self.chroot_dir.is_some()
+    }
+}
+
+/// Apply the policy to the calling process. Meant for the spawned child, never for Zephir itself.
+pub fn apply_unix_sandbox(policy: &mut SandboxPolicy) -> io::Result<()> {
+    if let Some(plan) = &policy.namespaces {
+        os_namespace::enter_namespaces(plan)?;
+    }
+
+    // A limit that cannot be set (e.g. above an unprivileged hard limit) fails the
+    // run rather than leaving the function unconstrained.
+    // CPU time; SIGXCPU at the soft limit tells a CPU-limit kill apart, SIGKILL follows a second later.
+    setrlimit(Resource::CPU, policy.cpu_time, policy.cpu_time.saturating_add(1))?;
     // Max address space (memory)
-    let _ = setrlimit(Resource::AS, memory_limit, memory_limit);
+    if let Some(memory_limit) = policy.memory_limit {
+        setrlimit(Resource::AS, memory_limit, memory_limit)?;
+    }
     // Max file size
-    let _ = setrlimit(Resource::FSIZE, size_limit, size_limit);
-
-    if is_root {
-        if let Some(dir) = chroot_dir {
-            // Chroot into the sandbox directory
-            chroot(dir).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("chroot failed: {e}")))?;
-            // Change to "/" inside the chroot
-            std::env::set_current_dir("/")?;
+    setrlimit(Resource::FSIZE, policy.size_limit, policy.size_limit)?;
+    if let Some(tasks) = policy.max_tasks {
+        setrlimit(Resource::NPROC, tasks, tasks)?;
+    }
+    if let Some(files) = policy.max_open_files {
+        setrlimit(Resource::NOFILE, files, files)?;
+    }
+    if let Some(stack) = policy.max_stack {
+        setrlimit(Resource::STACK, stack, stack)?;
+    }
+
+    if policy.is_root && let Some(dir) = &policy.chroot_dir {
+        // Chroot into the sandbox directory
+        chroot(dir.as_c_str()).map_err(io::Error::from)?;
+        // Change to "/" inside the chroot
+        chdir("/").map_err(io::Error::from)?;
+    }
+
+    // Filesystem confinement; rules refer to already-open fds, so this works inside the chroot too.
+    while let Some(ruleset) = policy.landlock.pop() {
+        os_landlock::restrict_self(ruleset)?;
+    }
+
+    if let Some(identity) = &policy.identity {
+        switch_identity(identity)?;
+    }
+
+    Ok(())
+}
+
+/// Switch to `identity` and make sure it stuck: every real, effective and saved
+/// id must match, and a non-root identity must not be able to regain root. Fork-safe.
+fn switch_identity(identity: &Identity) -> io::Result<()> {
+    let check = |result: libc::c_int| if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) };
+
+    unsafe {
+        if let Some(groups) = &identity.groups {
+            check(libc::setgroups(groups.len(), groups.as_ptr()))?;
         }
+        check(libc::setresgid(identity.gid, identity.gid, identity.gid))?;
+        check(libc::setresuid(identity.uid, identity.uid, identity.uid))?;
+        // Leaving uid 0 normally clears capabilities, but not in a user namespace whose
+        // root is not the creator's uid: drop them explicitly.
+        if identity.uid != 0 {
+            clear_capabilities()?;
+        }
+
+        let (mut ruid, mut euid, mut suid) = (0, 0, 0);
+        let (mut rgid, mut egid, mut sgid) = (0, 0, 0);
+        check(libc::getresuid(&mut ruid, &mut euid, &mut suid))?;
+        check(libc::getresgid(&mut rgid, &mut egid, &mut sgid))?;
+
+        let uids_match = [ruid, euid, suid].iter().all(|&id| id == identity.uid);
+        let gids_match = [rgid, egid, sgid].iter().all(|&id| id == identity.gid);
+        // Only the errno survives the trip back to the parent, so report EPERM.
+        if !uids_match || !gids_match || (identity.uid != 0 && libc::setuid(0) == 0) {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+    }
+
+    Ok(())
+}
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
 
-        // Drop privileges: switch to nobody/nogroup
-        let _ = setgid(Gid::from_raw(65534)); // nogroup
-        let _ = setuid(Uid::from_raw(65534)); // nobody
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// Empty the effective, permitted and inheritable capability sets. Fork-safe.
+fn clear_capabilities() -> io::Result<()> {
+    let header = CapUserHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let data = [CapUserData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
     }
+    Ok(())
+}
 
+/// Make `fd` available as `target` in the exec'd program. Fork-safe.
+pub fn inherit_fd(fd: RawFd, target: RawFd) -> io::Result<()> {
+    let result = if fd == target {
+        // dup2 would be a no-op and leave FD_CLOEXEC set.
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC)
+        }
+    } else {
+        unsafe { libc::dup2(fd, target) }
+    };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
     Ok(())
 }
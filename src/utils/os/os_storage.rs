@@ -0,0 +1,175 @@
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Space and inodes taken up by a sandbox directory.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StorageUsage {
+    pub bytes: u64,
+    pub inodes: u64,
+    /// A quota filesystem has no space or inodes left.
+    pub full: bool,
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))
+}
+
+/// Mount a tmpfs limited to `bytes` and, optionally, `inodes` on `dir`. Needs root.
+pub fn mount_quota(dir: &Path, bytes: u64, inodes: Option<u64>) -> io::Result<()> {
+    let mut options = format!("size={bytes},mode=0755");
+    if let Some(inodes) = inodes {
+        options.push_str(&format!(",nr_inodes={inodes}"));
+    }
+    let options = CString::new(options).expect("mount options contain no NUL");
+    let target = c_path(dir)?;
+
+    let result = unsafe {
+        libc::mount(
+            QUOTA_SOURCE.as_ptr(),
+            target.as_ptr(),
+            c"tmpfs".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV,
+            options.as_ptr().cast(),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Source name [`mount_quota`] gives its tmpfs, which tells it apart from any other.
+const QUOTA_SOURCE: &CStr = c"zephir-sandbox";
+
+/// Undo the octal escapes (`\040` for a space, ...) of a `/proc/self/mountinfo` field.
+fn unescape_mountinfo(field: &str) -> Vec<u8> {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                unescaped.push(byte);
+                i += 1;
+            }
+        }
+    }
+    unescaped
+}
+
+/// Whether the topmost mount on `mount_point` in `mountinfo` is a quota tmpfs.
+fn is_quota_mount_in(mountinfo: &str, mount_point: &Path) -> bool {
+    let mut is_quota = false;
+    for line in mountinfo.lines() {
+        // The mount point is the fifth field; filesystem type and source follow the `-` separator.
+        let fields: Vec<&str> = line.split(' ').collect();
+        let Some(separator) = fields.iter().skip(6).position(|field| *field == "-").map(|index| index + 6) else { continue };
+        if fields.get(4).map(|field| unescape_mountinfo(field)).as_deref() != Some(mount_point.as_os_str().as_bytes()) {
+            continue;
+        }
+        let source = fields.get(separator + 2).map(|field| unescape_mountinfo(field));
+        // Later lines are mounted on top of earlier ones.
+        is_quota = fields.get(separator + 1) == Some(&"tmpfs") && source.as_deref() == Some(QUOTA_SOURCE.to_bytes());
+    }
+    is_quota
+}
+
+/// Whether `dir` is a quota tmpfs mounted by [`mount_quota`], going by
+/// `/proc/self/mountinfo` so that no other tmpfs is ever mistaken for one.
+pub fn is_quota_mount(dir: &Path) -> bool {
+    let (Ok(mount_point), Ok(mountinfo)) = (dir.canonicalize(), std::fs::read_to_string("/proc/self/mountinfo")) else {
+        return false;
+    };
+    is_quota_mount_in(&mountinfo, &mount_point)
+}
+
+/// Lazily unmount `dir`, so open files cannot keep the removal from going ahead.
+pub fn unmount(dir: &Path) -> io::Result<()> {
+    let target = c_path(dir)?;
+    if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Current usage of `dir`: straight from the filesystem for a quota mount,
+/// otherwise by walking the tree (hard links counted once).
+pub fn usage(dir: &Path) -> io::Result<StorageUsage> {
+    if is_quota_mount(dir) {
+        let path = c_path(dir)?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(StorageUsage {
+            bytes: (stat.f_blocks - stat.f_bfree) * stat.f_frsize,
+            inodes: stat.f_files - stat.f_ffree,
+            full: stat.f_bavail == 0 || stat.f_favail == 0,
+        });
+    }
+
+    let mut usage = StorageUsage::default();
+    let mut seen = HashSet::new();
+    for entry in walkdir::WalkDir::new(dir).min_depth(1) {
+        let metadata = match entry.and_then(|entry| entry.metadata()) {
+            Ok(metadata) => metadata,
+            // The function may delete files while we walk.
+            Err(e) if e.io_error().is_some_and(|e| e.kind() == io::ErrorKind::NotFound) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if metadata.nlink() > 1 && !seen.insert((metadata.dev(), metadata.ino())) {
+            continue;
+        }
+        usage.bytes += metadata.blocks() * 512;
+        usage.inodes += 1;
+    }
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+40 22 0:35 / /tmp rw,nosuid,nodev shared:20 - tmpfs tmpfs rw
+41 22 0:36 / /srv/zephir-sandbox/123 rw,nosuid,nodev - tmpfs zephir-sandbox rw,size=1024k
+42 22 0:37 / /srv/with\\040space rw,nosuid,nodev - tmpfs zephir-sandbox rw,size=1024k
+43 22 0:38 / /srv/other rw,nosuid,nodev - tmpfs zephir-sandbox rw
+44 43 0:39 / /srv/other rw,nosuid,nodev - tmpfs tmpfs rw
+";
+
+    #[test]
+    fn finds_quota_mounts_by_source() {
+        assert!(is_quota_mount_in(MOUNTINFO, Path::new("/srv/zephir-sandbox/123")));
+        assert!(is_quota_mount_in(MOUNTINFO, Path::new("/srv/with space")));
+    }
+
+    #[test]
+    fn ignores_other_mounts() {
+        assert!(!is_quota_mount_in(MOUNTINFO, Path::new("/tmp")));
+        assert!(!is_quota_mount_in(MOUNTINFO, Path::new("/")));
+        assert!(!is_quota_mount_in(MOUNTINFO, Path::new("/srv/zephir-sandbox")));
+        assert!(!is_quota_mount_in(MOUNTINFO, Path::new("/srv/with\\040space")));
+    }
+
+    #[test]
+    fn only_the_topmost_mount_counts() {
+        assert!(!is_quota_mount_in(MOUNTINFO, Path::new("/srv/other")));
+    }
+
+    #[test]
+    fn unescapes_octal_sequences() {
+        assert_eq!(unescape_mountinfo(r"a\040b\011c\134d"), b"a b\tc\\d");
+        assert_eq!(unescape_mountinfo(r"trailing\04"), br"trailing\04");
+    }
+}